@@ -1,103 +1,592 @@
-use std::env;
-use crate::mach;
-use crate::repo;
+use std::io::Write;
+use std::process;
+use arc::mach;
+use arc::repo;
+use arc::log;
+use arc::status;
+use arc::diff;
+use arc::rev;
+use arc::store;
+use arc::merge;
+use arc::hunk;
+use arc::revid::RevID;
+use arc::{ArcError, LocalChanges, MergeOutcome, Result, SyncOutcome};
 
-pub fn command(cmd_name: String, args: Vec<&String>) {
-    let cwd = mach::get_cwd();
+// A command line, as main.rs read it
+pub enum Command {
+    Init { directory: String },
+    Print { path: String },
+    Status { porcelain: bool },
+    Add { paths: Vec<String>, all: bool, force: bool },
+    Remove { paths: Vec<String>, cached: bool, force: bool },
+    Commit { staging: Staging, message: Option<String>, message_file: Option<String> },
+    Reset { paths: Vec<String> },
+    Restore { source: Option<String>, paths: Vec<String> },
+    Checkout { rev: String, local: LocalChanges },
+    // Without a trunk, the other revision is merged into the current one
+    Merge { trunk: Option<String>, other: String, local: LocalChanges },
+    MergeContinue,
+    MergeAbort,
+    // Without files to mark, the conflicts are listed
+    Resolve { mark: Vec<String> },
+    Log { rev: Option<String>, limit: Option<usize>, oneline: bool, paths: Vec<String> },
+    Diff { revs: Vec<String>, staged: bool, format: DiffFormat, paths: Vec<String> },
+    ListBranches,
+    CreateBranch { name: String, rev: Option<String> },
+    DeleteBranch { name: String },
+    RenameBranch { old_name: String, new_name: String },
+    ListTags,
+    CreateTag { name: String, rev: Option<String>, message: Option<String> },
+    DeleteTag { name: String },
+    Heads,
+    Cat { rev: String, path: String },
+    Clone { src: String, dst: String },
+    StashPush { message: Option<String> },
+    StashPop { index: usize },
+    StashList,
+    StashDrop { index: usize },
+    StashShow { index: usize, patch: bool },
+    Push,
+    Pull { local: LocalChanges },
+}
+
+// What commit stages before committing
+pub enum Staging {
+    // Nothing, the index is committed as it is
+    Index,
+    All,
+    Interactive,
+    // The hunks in the given patch file
+    Patch(String),
+}
+
+pub enum DiffFormat {
+    Patch,
+    Stat,
+    NameOnly,
+}
+
+pub fn run(command: Command) -> Result<()> {
+    let cwd = mach::get_cwd()?;
 
-    match &cmd_name[..] {
-        "init" => {
-            let repo_root_path = &args[0];
-            repo::init(repo_root_path);
+    match command {
+        Command::Init { directory } => {
+            repo::init(&directory)?;
+            println!("Initialized repo @ {}", directory);
         },
-        "print" => {
-            let repo_root_path = &args[0];
-            let r = repo::open(repo_root_path);
+        Command::Print { path } => {
+            let r = repo::open(&path)?;
             println!("{}", r);
         },
-        "add" => {
-            let file_abs_path = mach::join_paths(&cwd, &args[0]);
-            let repo_root_path = mach::find_repo_root_path(&file_abs_path);
-            let file_rel_path = mach::find_rel_path(&repo_root_path, &file_abs_path);
+        Command::Status { porcelain } => {
+            let repo = open_repo(&cwd)?;
+            let s = status::compute(&repo)?;
+            if porcelain {
+                print!("{}", s.porcelain());
+            } else {
+                if let Some(b) = repo.get_active_branch() {
+                    println!("On branch {}", b);
+                }
+                println!("On revision {}", repo.get_head_rev());
+                if let Some(state) = merge::load_state(&repo.arc_path)? {
+                    println!("Merging {} into {}", state.other, state.trunk);
+                    for f in &state.unresolved() {
+                        println!("  unresolved: {}", f);
+                    }
+                }
+                print!("{}", s);
+            }
+        },
+        Command::Add { paths, all, force } => {
+            // The repo is the one the first path is in, so files can be added from outside it
+            let mut r = match paths.first() {
+                Some(p) => open_repo(&mach::normalize_path(&mach::join_paths(&cwd, p)))?,
+                None => open_repo(&cwd)?,
+            };
+            let rel_paths: Vec<String> = if all {
+                vec![String::new()]
+            } else {
+                paths.iter().map(|p| rel_path(&r, &cwd, p)).collect::<Result<_>>()?
+            };
             
-            let mut r = repo::open(&repo_root_path);
-            r.add_file(&file_rel_path);
-            r.save();
+            let tracked = r.get_tracked_files().clone();
+            let staged = r.add_paths(&rel_paths, force)?;
+            r.save()?;
+            for f in &staged {
+                if tracked.contains(f) {
+                    println!("Staged changes @ {}", f);
+                } else {
+                    println!("Added to tracked files @ {}", f);
+                }
+            }
+            if staged.is_empty() {
+                println!("Nothing new to stage");
+            }
             
             println!("{}", r);
         },
-        "remove" => {
-            let file_abs_path = mach::join_paths(&cwd, &args[0]);
-            let repo_root_path = mach::find_repo_root_path(&file_abs_path);
-            let file_rel_path = mach::find_rel_path(&repo_root_path, &file_abs_path);
+        Command::Remove { paths, cached, force } => {
+            let mut r = open_repo(&mach::normalize_path(&mach::join_paths(&cwd, &paths[0])))?;
+            let rel_paths: Vec<String> = paths.iter().map(|p| rel_path(&r, &cwd, p)).collect::<Result<_>>()?;
             
-            let mut r = repo::open(&repo_root_path);
-            r.remove_file(&file_rel_path);
-            r.save();
+            for f in r.remove_paths(&rel_paths, cached, force)? {
+                println!("Removed from tracked files @ {}", f);
+            }
+            r.save()?;
             
             println!("{}", r);
         },
-        "commit" => {
-            let repo_root_path = mach::find_repo_root_path(&cwd);
-            let mut repo = repo::open(&repo_root_path);
-            repo.commit();
-            repo.save();
+        Command::Commit { staging, message, message_file } => {
+            let mut repo = open_repo(&cwd)?;
+            match staging {
+                Staging::Index => {},
+                Staging::All => {
+                    repo.stage_tracked()?;
+                },
+                Staging::Interactive => {
+                    let picked = pick_hunks(&repo)?;
+                    if picked.is_empty() {
+                        println!("No hunks picked, nothing committed");
+                        return Ok(());
+                    }
+                    for (f, content) in &picked {
+                        repo.stage_content(f, content.as_bytes())?;
+                    }
+                },
+                Staging::Patch(patch_file) => {
+                    let patch_text = mach::read_line(&cwd, &patch_file)?;
+                    for (f, content) in hunk::apply_patch(&repo, &patch_text)? {
+                        repo.stage_content(&f, content.as_bytes())?;
+                    }
+                },
+            }
+            let message = match message {
+                Some(m) => m,
+                None => read_commit_message(&repo, &cwd, message_file.as_ref())?,
+            };
+            let rev = repo.commit(&message)?;
+            repo.save()?;
+            println!("Committed -> {}", rev.get_id());
+        },
+        Command::Reset { paths } => {
+            let mut repo = open_repo(&cwd)?;
+            let rel_paths: Vec<String> = if paths.is_empty() {
+                vec![String::new()]
+            } else {
+                paths.iter().map(|p| rel_path(&repo, &cwd, p)).collect::<Result<_>>()?
+            };
+            
+            let unstaged = repo.reset(&rel_paths)?;
+            repo.save()?;
+            for f in &unstaged {
+                println!("Unstaged {}", f);
+            }
+            if unstaged.is_empty() {
+                println!("Nothing to unstage");
+            }
+        },
+        Command::Restore { source, paths } => {
+            let mut repo = open_repo(&cwd)?;
+            let rel_paths: Vec<String> = paths.iter().map(|p| rel_path(&repo, &cwd, p)).collect::<Result<_>>()?;
+            
+            let rev_id = repo.resolve_known(&source.unwrap_or_else(|| "HEAD".to_string()))?;
+            for f in repo.restore(&rev_id.to_string(), &rel_paths)? {
+                println!("Restored {} from {}", f, rev_id);
+            }
+            repo.save()?;
+        },
+        Command::Checkout { rev, local } => {
+            let mut repo = open_repo(&cwd)?;
+            let rev = repo.checkout(&rev, local)?;
+            repo.save()?;
+            match repo.get_active_branch() {
+                Some(b) => println!("Checked out branch {} at {}", b, rev.get_id()),
+                None => println!("Checked out {}", rev.get_id()),
+            }
+        },
+        Command::MergeContinue => {
+            let mut repo = open_repo(&cwd)?;
+            let branch = repo.get_active_branch().cloned();
+            let rev = repo.merge_continue()?;
+            println!("Merged {} and {} -> {}", rev.get_parent_trunk_id(), rev.get_parent_other_id(), rev.get_id());
+            report_left_branch(&repo, branch);
+        },
+        Command::MergeAbort => {
+            let mut repo = open_repo(&cwd)?;
+            let rev_id = repo.merge_abort()?;
+            println!("Merge aborted, back at {}", rev_id);
         },
-        "checkout" => {
-            let repo_root_path = mach::find_repo_root_path(&cwd);
-            let mut repo = repo::open(&repo_root_path);
-            repo.checkout(&args[0]);
-            repo.save();
+        Command::Merge { trunk, other, local } => {
+            let mut repo = open_repo(&cwd)?;
+            let trunk = trunk.unwrap_or_else(|| repo.get_head_rev_str());
+            let outcome = repo.merge(&trunk, &other, local)?;
+            report_merge(&outcome);
+            match outcome.rev() {
+                Some(rev) => {
+                    advance(&mut repo, rev.get_id(), local)?;
+                    println!("Merged -> {}", rev.get_id_str());
+                },
+                None => report_conflicts(),
+            }
         },
-        "merge" => {
-            if args.len() >= 2 {
-                let repo_root_path = mach::find_repo_root_path(&cwd);
-                let mut repo = repo::open(&repo_root_path);
-                let rev = repo.merge(&args[0], &args[1]);
-                repo.checkout(&rev.get_id_str());
-                println!("Merged -> {}", rev.get_id_str());
-            } else if args.len() >= 1 {
-                let repo_root_path = mach::find_repo_root_path(&cwd);
-                let mut repo = repo::open(&repo_root_path);
-                let rev = repo.merge(&repo.get_head_rev_str(), &args[0]);
-                repo.checkout(&rev.get_id_str());
-                println!("Merged -> {}", rev.get_id_str());
+        Command::Resolve { mark } => {
+            let mut repo = open_repo(&cwd)?;
+            if !mark.is_empty() {
+                for p in &mark {
+                    let file_rel_path = rel_path(&repo, &cwd, p)?;
+                    repo.resolve_mark(&file_rel_path)?;
+                    println!("Marked as resolved @ {}", file_rel_path);
+                }
+            } else {
+                let state = merge::load_state(&repo.arc_path)?.ok_or(ArcError::NoMergeInProgress)?;
+                for f in &state.conflicts {
+                    let mark = if state.resolved.contains(f) { "R" } else { "U" };
+                    println!("{} {}", mark, f);
+                }
             }
         },
-        "clone" => {
-            let src_repo_path = mach::find_repo_root_path(&args[0]);
-            let dst_repo_path = &args[1];
+        Command::Log { rev, limit, oneline, paths } => {
+            let repo = open_repo(&cwd)?;
             
-            repo::init(&dst_repo_path);
+            let start_id = match rev {
+                Some(r) => repo.resolve_known(&r)?,
+                None => repo.get_head_rev(),
+            };
+            let rel_paths: Vec<String> = paths.iter().map(|p| rel_path(&repo, &cwd, p)).collect::<Result<_>>()?;
+            if start_id.is_empty() {
+                println!("No revisions yet");
+            } else {
+                for line in log::format_log(&repo, &start_id, limit, &rel_paths, oneline)? {
+                    println!("{}", line);
+                }
+            }
+        },
+        Command::Diff { revs, staged, format, paths } => {
+            let repo = open_repo(&cwd)?;
             
-            let src_r = repo::open(&src_repo_path);
-            let mut dst_r = repo::open(&dst_repo_path);
+            let revs: Vec<rev::Rev> = revs.iter().map(|r| rev::open(&repo, &repo.resolve_known(r)?)).collect::<Result<_>>()?;
+            let rel_paths: Vec<String> = paths.iter().map(|p| rel_path(&repo, &cwd, p)).collect::<Result<_>>()?;
             
-            dst_r.set_upstream(&src_repo_path);
-            dst_r.sync(&src_r);
-            dst_r.checkout(&src_r.get_head_rev_str());
+            let head_rev = repo.head_rev()?;
+            let diffs = match revs.len() {
+                0 if staged => diff::diff_staged(&repo, head_rev.as_ref(), &rel_paths)?,
+                0 => diff::diff_unstaged(&repo, &rel_paths)?,
+                1 if staged => diff::diff_staged(&repo, Some(&revs[0]), &rel_paths)?,
+                1 => diff::diff_worktree(&repo, Some(&revs[0]), &rel_paths)?,
+                _ => diff::diff_revs(&repo, Some(&revs[0]), Some(&revs[1]), &rel_paths)?,
+            };
+            match format {
+                DiffFormat::Stat => print!("{}", diff::format_stat(&diffs)),
+                DiffFormat::NameOnly => print!("{}", diff::format_name_only(&diffs)),
+                DiffFormat::Patch => print!("{}", diff::format_patch(&diffs)),
+            }
+        },
+        Command::ListBranches => {
+            let repo = open_repo(&cwd)?;
+            for (name, id) in repo.get_branches()? {
+                let r = rev::open(&repo, &id)?;
+                let marker = if repo.get_active_branch() == Some(&name) { "*" } else { " " };
+                println!("{} {} {} {}", marker, name, id, r.get_summary());
+            }
+        },
+        Command::CreateBranch { name, rev } => {
+            let mut repo = open_repo(&cwd)?;
+            let rev = rev.unwrap_or_else(|| repo.get_head_rev_str());
+            let rev_id = repo.create_branch(&name, &rev)?;
+            println!("Created branch {} at {}", name, rev_id);
+        },
+        Command::DeleteBranch { name } => {
+            let mut repo = open_repo(&cwd)?;
+            repo.delete_branch(&name)?;
+            println!("Deleted branch {}", name);
+        },
+        Command::RenameBranch { old_name, new_name } => {
+            let mut repo = open_repo(&cwd)?;
+            repo.rename_branch(&old_name, &new_name)?;
+            println!("Renamed branch {} to {}", old_name, new_name);
+        },
+        Command::ListTags => {
+            let repo = open_repo(&cwd)?;
+            for (name, tag) in repo.get_tags()? {
+                match &tag.message {
+                    Some(m) => println!("{} {} {}", name, tag.rev_id, m.lines().next().unwrap_or("")),
+                    None => println!("{} {}", name, tag.rev_id),
+                }
+            }
+        },
+        Command::CreateTag { name, rev, message } => {
+            let mut repo = open_repo(&cwd)?;
+            let rev = rev.unwrap_or_else(|| repo.get_head_rev_str());
+            let rev_id = repo.create_tag(&name, &rev, message.as_ref())?;
+            println!("Tagged {} as {}", rev_id, name);
+        },
+        Command::DeleteTag { name } => {
+            let mut repo = open_repo(&cwd)?;
+            repo.delete_tag(&name)?;
+            println!("Deleted tag {}", name);
+        },
+        Command::Heads => {
+            let repo = open_repo(&cwd)?;
+            
+            for id in repo.get_heads() {
+                let r = rev::open(&repo, &id)?;
+                let marker = if id == repo.get_head_rev() { "*" } else { " " };
+                println!("{} {} {} {} {}", marker, id, mach::format_timestamp(r.get_timestamp()), r.get_author(), r.get_summary());
+            }
+        },
+        Command::Cat { rev, path } => {
+            let repo = open_repo(&cwd)?;
+            
+            let rev_id = repo.resolve_known(&rev)?;
+            let rev = rev::open(&repo, &rev_id)?;
+            
+            let file_rel_path = rel_path(&repo, &cwd, &path)?;
+            let h = rev.get_blob(&file_rel_path).ok_or(ArcError::NotInRevision(file_rel_path.clone(), rev_id))?;
+            std::io::stdout().write_all(&store::get(&repo.arc_path, h)?).map_err(ArcError::io("stdout"))?;
+        },
+        Command::Clone { src, dst } => {
+            // The source is remembered as the upstream, so it must not depend on the cwd
+            let src_r = open_repo(&mach::normalize_path(&mach::join_paths(&cwd, &src)))?;
+            let dst_repo_path = mach::normalize_path(&mach::join_paths(&cwd, &dst));
+            
+            let mut dst_r = repo::init(&dst_repo_path)?;
+            println!("Initialized repo @ {}", dst_repo_path);
+            dst_r.set_upstream(&src_r.root_path);
+            let outcome = dst_r.sync(&src_r)?;
+            report_sync(&dst_r, &src_r, &outcome);
+            // An empty source has nothing to check out
+            if !src_r.get_head_rev().is_empty() {
+                advance(&mut dst_r, &src_r.get_head_rev(), LocalChanges::Refuse)?;
+            }
+        },
+        Command::StashPush { message } => {
+            let mut repo = open_repo(&cwd)?;
+            match repo.stash_push(message.as_deref())? {
+                Some(r) => println!("Saved stash@{{0}}: {}", r.get_summary()),
+                None => println!("No local changes to save"),
+            }
+        },
+        Command::StashPop { index } => {
+            let mut repo = open_repo(&cwd)?;
+            let conflicts = repo.stash_pop(index)?;
+            if !conflicts.is_empty() {
+                for f in &conflicts {
+                    println!("Conflict in {}", f);
+                }
+                eprintln!("Applying stash@{{{}}} failed; fix the conflicts and commit, then drop the stash with arc stash drop", index);
+                process::exit(1);
+            }
+            println!("Applied and dropped stash@{{{}}}", index);
+        },
+        Command::StashList => {
+            let repo = open_repo(&cwd)?;
+            for (n, r) in repo.get_stashes()?.iter().enumerate() {
+                println!("stash@{{{}}}: {}", n, r.get_summary());
+            }
+        },
+        Command::StashDrop { index } => {
+            let mut repo = open_repo(&cwd)?;
+            let r = repo.stash_drop(index)?;
+            println!("Dropped stash@{{{}}} ({})", index, r.get_id());
         },
-        "push" => {
-            let local_repo_path = mach::find_repo_root_path(&cwd);
-            let local_repo = repo::open(&local_repo_path);
+        Command::StashShow { index, patch } => {
+            let repo = open_repo(&cwd)?;
+            let stashes = repo.get_stashes()?;
+            let r = stashes.get(index).ok_or(ArcError::NoSuchStash(index))?;
+            let base = rev::open(&repo, r.get_parent_trunk_id())?;
+            let diffs = diff::diff_revs(&repo, Some(&base), Some(r), &[])?;
+            if patch {
+                print!("{}", diff::format_patch(&diffs));
+            } else {
+                print!("{}", diff::format_stat(&diffs));
+            }
+        },
+        Command::Push => {
+            let local_repo = open_repo(&cwd)?;
             
-            let mut upstream_repo = repo::open(local_repo.get_upstream());
-            upstream_repo.sync(&local_repo);
+            let mut upstream_repo = repo::open(local_repo.get_upstream())?;
+            let outcome = upstream_repo.sync(&local_repo)?;
+            report_sync(&upstream_repo, &local_repo, &outcome);
             
-            let rev = upstream_repo.merge(&upstream_repo.get_head_rev_str(), &local_repo.get_head_rev_str());
-            upstream_repo.checkout(&rev.get_id_str());
+            // Conflicts can't be fixed in someone else's working tree
+            let outcome = upstream_repo.merge(&upstream_repo.get_head_rev_str(), &local_repo.get_head_rev_str(), LocalChanges::Refuse)?;
+            report_merge(&outcome);
+            match outcome.rev() {
+                Some(rev) => advance(&mut upstream_repo, rev.get_id(), LocalChanges::Refuse)?,
+                None => {
+                    upstream_repo.merge_abort()?;
+                    return Err(ArcError::PushConflict);
+                }
+            }
         },
-        "pull" => {
-            let local_repo_path = mach::find_repo_root_path(&cwd);
-            let mut local_repo = repo::open(&local_repo_path);
+        Command::Pull { local } => {
+            let mut local_repo = open_repo(&cwd)?;
             
-            let upstream_repo = repo::open(local_repo.get_upstream());
-            local_repo.sync(&upstream_repo);
+            let upstream_repo = repo::open(local_repo.get_upstream())?;
+            let outcome = local_repo.sync(&upstream_repo)?;
+            report_sync(&local_repo, &upstream_repo, &outcome);
             
-            let rev = local_repo.merge(&upstream_repo.get_head_rev_str(), &local_repo.get_head_rev_str());
-            local_repo.checkout(&rev.get_id_str());
+            let outcome = local_repo.merge(&upstream_repo.get_head_rev_str(), &local_repo.get_head_rev_str(), local)?;
+            report_merge(&outcome);
+            match outcome.rev() {
+                Some(rev) => advance(&mut local_repo, rev.get_id(), local)?,
+                None => report_conflicts(),
+            }
+        },
+    }
+    Ok(())
+}
+
+// Opens the repo that the given path is in
+fn open_repo(path: &String) -> Result<repo::Repo> {
+    let repo_root_path = mach::find_repo_root_path(path);
+    if repo_root_path.is_empty() {
+        return Err(ArcError::NotARepo(path.clone()));
+    }
+    repo::open(&repo_root_path)
+}
+
+// A path given on the command line, relative to the repo root; paths
+// outside the repo are refused rather than read as the whole tree
+fn rel_path(repo: &repo::Repo, cwd: &String, p: &String) -> Result<String> {
+    let full_path = mach::normalize_path(&mach::join_paths(cwd, p));
+    if full_path != repo.root_path && !full_path.starts_with(&format!("{}/", repo.root_path)) {
+        return Err(ArcError::BadArgument(format!("{} is outside the repository", p)));
+    }
+    Ok(mach::find_rel_path(&repo.root_path, &full_path))
+}
+
+// Moves to the revision, telling when that leaves the active branch behind
+fn advance(repo: &mut repo::Repo, rev_id: &RevID, local: LocalChanges) -> Result<()> {
+    let branch = repo.get_active_branch().cloned();
+    repo.advance(&rev_id.to_string(), local)?;
+    report_left_branch(repo, branch);
+    println!("Checked out {}", rev_id);
+    Ok(())
+}
+
+fn report_left_branch(repo: &repo::Repo, branch: Option<String>) {
+    if let Some(name) = branch {
+        if repo.get_active_branch().is_none() {
+            println!("Left branch {}, {} doesn't descend from it", name, repo.get_head_rev());
+        }
+    }
+}
+
+fn report_sync(dst: &repo::Repo, src: &repo::Repo, outcome: &SyncOutcome) {
+    for name in &outcome.skipped_tags {
+        println!("Skipped tag {}, a branch has that name", name);
+    }
+    for (name, local, other) in &outcome.kept_tags {
+        println!("Kept local tag {} at {}, the other repo has it at {}", name, local, other);
+    }
+    println!("Synchronized {} with {}", dst.root_path, src.root_path);
+}
+
+fn report_merge(outcome: &MergeOutcome) {
+    match outcome {
+        MergeOutcome::UpToDate(rev) => println!("Already up to date: {}", rev.get_id()),
+        MergeOutcome::FastForward(rev) => println!("Fast-forward -> {}", rev.get_id()),
+        MergeOutcome::Merged { rev, bases } => {
+            report_bases(bases);
+            println!("Merged {} and {} -> {}", rev.get_parent_trunk_id(), rev.get_parent_other_id(), rev.get_id());
         },
-        _ => println!("unknown command: {}", cmd_name),
+        MergeOutcome::Conflicts { files, bases } => {
+            report_bases(bases);
+            for f in files {
+                println!("Conflict in {}", f);
+            }
+        },
+    }
+}
+
+fn report_bases(bases: &[RevID]) {
+    if bases.len() > 1 {
+        let ids: Vec<String> = bases.iter().map(|id| id.to_string()).collect();
+        println!("Multiple common ancestors, merging them first: {}", ids.join(", "));
     }
 }
 
+fn report_conflicts() {
+    eprintln!("Automatic merge failed; fix the conflicts, mark them with arc resolve --mark <path>, then run arc merge --continue (or arc merge --abort)");
+    process::exit(1);
+}
+
+// Walks the hunks changed since the files were staged, asking which to
+// commit. Gives the content to stage for each file with a hunk picked; the
+// others keep what is staged for them.
+fn pick_hunks(repo: &repo::Repo) -> Result<Vec<(String, String)>> {
+    let mut picked = Vec::new();
+    let mut quit = false;
+    for file in hunk::find_changes(repo)? {
+        let mut hunks = file.hunks;
+        let mut chosen = Vec::new();
+        let mut i = 0;
+        if !quit {
+            println!("diff --arc a/{} b/{}", file.path, file.path);
+        }
+        while i < hunks.len() && !quit {
+            print!("{}", hunks[i].text());
+            print!("Commit this hunk [y,n,s,e,q,?]? ");
+            std::io::stdout().flush().map_err(ArcError::io("stdout"))?;
+            let mut answer = String::new();
+            if std::io::stdin().read_line(&mut answer).map_err(ArcError::io("stdin"))? == 0 {
+                println!();
+                quit = true;
+                break;
+            }
+            
+            match answer.trim() {
+                "y" => {
+                    chosen.push(hunks[i].clone());
+                    i += 1;
+                },
+                "n" => i += 1,
+                "s" => match hunks[i].split() {
+                    Some(parts) => {
+                        println!("Split into {} hunks", parts.len());
+                        hunks.splice(i..i + 1, parts);
+                    },
+                    None => println!("This hunk can't be split"),
+                },
+                "e" => match edit_hunk(repo, &hunks[i]) {
+                    Ok(h) => {
+                        chosen.push(h);
+                        i += 1;
+                    },
+                    Err(e) => println!("{}", e),
+                },
+                "q" => quit = true,
+                _ => println!("y - commit this hunk\nn - leave this hunk out\ns - split this hunk into smaller ones\ne - edit this hunk by hand\nq - leave this and all later hunks out"),
+            }
+        }
+        
+        if !chosen.is_empty() {
+            picked.push((file.path.clone(), hunk::apply_hunks(&file.old, &chosen)?));
+        }
+    }
+    Ok(picked)
+}
+
+fn edit_hunk(repo: &repo::Repo, h: &hunk::Hunk) -> Result<hunk::Hunk> {
+    let template = format!("# Edit the hunk to commit. Keep the ' ' and '-' lines as they are; delete\n# '+' lines to leave them out, or turn a '-' line into a ' ' line to keep it.\n# Lines starting with '#' are ignored.\n{}", h.text());
+    mach::write_string(&repo.arc_path, &"HUNK_EDIT.diff".to_string(), &template)?;
+    mach::run_editor(&mach::join_paths(&repo.arc_path, &"HUNK_EDIT.diff".to_string()))?;
+    h.edit(&mach::read_line(&repo.arc_path, &"HUNK_EDIT.diff".to_string())?)
+}
+
+// The message from the given file, or else from the editor
+fn read_commit_message(repo: &repo::Repo, cwd: &String, message_file: Option<&String>) -> Result<String> {
+    let raw = match message_file {
+        Some(f) => mach::read_line(cwd, f)?,
+        None => {
+            let template = "\n# Please enter the commit message for your changes. Lines starting\n# with '#' will be ignored, and an empty message aborts the commit.\n".to_string();
+            mach::write_string(&repo.arc_path, &"COMMIT_EDITMSG".to_string(), &template)?;
+            mach::run_editor(&mach::join_paths(&repo.arc_path, &"COMMIT_EDITMSG".to_string()))?;
+            mach::read_line(&repo.arc_path, &"COMMIT_EDITMSG".to_string())?
+        },
+    };
+    
+    let lines: Vec<&str> = raw.lines().filter(|l| !l.starts_with('#')).collect();
+    Ok(lines.join("\n").trim().to_string())
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use crate::mach;
+use crate::repo;
+use crate::rev;
+use crate::store;
+use crate::error::Result;
+
+pub struct FileDiff {
+    pub path: String,
+    pub old: Option<Vec<u8>>,
+    pub new: Option<Vec<u8>>,
+}
+
+fn as_text(data: &Option<Vec<u8>>) -> Option<&str> {
+    match data {
+        Some(d) => std::str::from_utf8(d).ok(),
+        None => Some(""),
+    }
+}
+
+impl FileDiff {
+    pub fn is_binary(&self) -> bool {
+        as_text(&self.old).is_none() || as_text(&self.new).is_none()
+    }
+    
+    // Unified diff with a/ and b/ headers
+    pub fn patch(&self) -> String {
+        let mut out = format!("diff --arc a/{} b/{}\n", self.path, self.path);
+        if self.old.is_none() {
+            out.push_str("new file\n");
+        } else if self.new.is_none() {
+            out.push_str("deleted file\n");
+        }
+        
+        if self.is_binary() {
+            out.push_str(&format!("Binary files a/{} and b/{} differ\n", self.path, self.path));
+            return out;
+        }
+        
+        let old_name = if self.old.is_some() { format!("a/{}", self.path) } else { "/dev/null".to_string() };
+        let new_name = if self.new.is_some() { format!("b/{}", self.path) } else { "/dev/null".to_string() };
+        out.push_str(&format!("--- {}\n+++ {}\n", old_name, new_name));
+        
+        // diffy names the sides "original" and "modified", so skip its header
+        let patch = diffy::create_patch(as_text(&self.old).unwrap(), as_text(&self.new).unwrap()).to_string();
+        for l in patch.lines().skip(2) {
+            out.push_str(l);
+            out.push('\n');
+        }
+        out
+    }
+    
+    // (insertions, deletions)
+    pub fn stat(&self) -> (usize, usize) {
+        if self.is_binary() {
+            return (0, 0);
+        }
+        let patch = diffy::create_patch(as_text(&self.old).unwrap(), as_text(&self.new).unwrap());
+        let mut counts = (0, 0);
+        for h in patch.hunks() {
+            for l in h.lines() {
+                match l {
+                    diffy::Line::Insert(_) => counts.0 += 1,
+                    diffy::Line::Delete(_) => counts.1 += 1,
+                    diffy::Line::Context(_) => {},
+                }
+            }
+        }
+        counts
+    }
+}
+
+/*
+ * Collecting the changed files
+ */
+// A path names a file, or everything below a directory ("" is the whole tree)
+pub fn matches_path(f: &str, p: &str) -> bool {
+    p.is_empty() || f == p || f.starts_with(&format!("{}/", p))
+}
+
+pub fn matches_paths(f: &str, paths: &[String]) -> bool {
+    paths.is_empty() || paths.iter().any(|p| matches_path(f, p))
+}
+
+fn rev_blobs(rev: Option<&rev::Rev>) -> BTreeMap<String, String> {
+    let mut blobs = BTreeMap::new();
+    if let Some(r) = rev {
+        for f in r.get_files() {
+            let h = r.get_blob(&f).unwrap().clone();
+            blobs.insert(f, h);
+        }
+    }
+    blobs
+}
+
+pub fn diff_revs(repo: &repo::Repo, old_rev: Option<&rev::Rev>, new_rev: Option<&rev::Rev>, paths: &[String]) -> Result<Vec<FileDiff>> {
+    diff_blobs(repo, &rev_blobs(old_rev), &rev_blobs(new_rev), paths)
+}
+
+// What a commit would add to the revision: the index against it
+pub fn diff_staged(repo: &repo::Repo, old_rev: Option<&rev::Rev>, paths: &[String]) -> Result<Vec<FileDiff>> {
+    diff_blobs(repo, &rev_blobs(old_rev), repo.get_index(), paths)
+}
+
+fn diff_blobs(repo: &repo::Repo, old_blobs: &BTreeMap<String, String>, new_blobs: &BTreeMap<String, String>, paths: &[String]) -> Result<Vec<FileDiff>> {
+    let mut files: Vec<&String> = old_blobs.keys().chain(new_blobs.keys()).collect();
+    files.sort();
+    files.dedup();
+    
+    let mut diffs = Vec::new();
+    for f in files {
+        let (old_h, new_h) = (old_blobs.get(f), new_blobs.get(f));
+        if old_h != new_h && matches_paths(f, paths) {
+            diffs.push(FileDiff {
+                path: f.clone(),
+                old: old_h.map(|h| store::get(&repo.arc_path, h)).transpose()?,
+                new: new_h.map(|h| store::get(&repo.arc_path, h)).transpose()?,
+            });
+        }
+    }
+    Ok(diffs)
+}
+
+// The working tree side is every tracked file that is still on disk
+pub fn diff_worktree(repo: &repo::Repo, old_rev: Option<&rev::Rev>, paths: &[String]) -> Result<Vec<FileDiff>> {
+    worktree_diffs(repo, &rev_blobs(old_rev), paths)
+}
+
+// The changes that aren't staged yet: the working tree against the index
+pub fn diff_unstaged(repo: &repo::Repo, paths: &[String]) -> Result<Vec<FileDiff>> {
+    worktree_diffs(repo, repo.get_index(), paths)
+}
+
+fn worktree_diffs(repo: &repo::Repo, old_blobs: &BTreeMap<String, String>, paths: &[String]) -> Result<Vec<FileDiff>> {
+    let mut new_blobs = BTreeMap::new();
+    for f in repo.get_tracked_files() {
+        if mach::check_path(&mach::join_paths(&repo.root_path, f)) {
+            new_blobs.insert(f.clone(), store::hash_file(&repo.root_path, f)?);
+        }
+    }
+    
+    let mut files: Vec<&String> = old_blobs.keys().chain(new_blobs.keys()).collect();
+    files.sort();
+    files.dedup();
+    
+    let mut diffs = Vec::new();
+    for f in files {
+        let (old_h, new_h) = (old_blobs.get(f), new_blobs.get(f));
+        if old_h != new_h && matches_paths(f, paths) {
+            diffs.push(FileDiff {
+                path: f.clone(),
+                old: old_h.map(|h| store::get(&repo.arc_path, h)).transpose()?,
+                new: new_h.map(|_| mach::read_bytes(&repo.root_path, f)).transpose()?,
+            });
+        }
+    }
+    Ok(diffs)
+}
+
+/*
+ * Output
+ */
+pub fn format_patch(diffs: &[FileDiff]) -> String {
+    diffs.iter().map(|d| d.patch()).collect()
+}
+
+pub fn format_name_only(diffs: &[FileDiff]) -> String {
+    diffs.iter().map(|d| format!("{}\n", d.path)).collect()
+}
+
+pub fn format_stat(diffs: &[FileDiff]) -> String {
+    let mut out = String::new();
+    let stats: Vec<(usize, usize)> = diffs.iter().map(|d| d.stat()).collect();
+    let name_width = diffs.iter().map(|d| d.path.len()).max().unwrap_or(0);
+    let (mut insertions, mut deletions) = (0, 0);
+    for (d, (ins, del)) in diffs.iter().zip(stats.iter()) {
+        if d.is_binary() {
+            out.push_str(&format!(" {:width$} | Bin\n", d.path, width = name_width));
+        } else {
+            out.push_str(&format!(" {:width$} | {} {}{}\n", d.path, ins + del, "+".repeat(*ins), "-".repeat(*del), width = name_width));
+        }
+        insertions += ins;
+        deletions += del;
+    }
+    out.push_str(&format!(" {} file{} changed, {} insertion{}(+), {} deletion{}(-)\n",
+        diffs.len(), if diffs.len() == 1 { "" } else { "s" },
+        insertions, if insertions == 1 { "" } else { "s" },
+        deletions, if deletions == 1 { "" } else { "s" }));
+    out
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use crate::revid::RevID;
+
+/*
+ * Errors
+ *
+ * Every failure arc can report. Each kind has its own process exit code so
+ * scripts can tell them apart: 1 is left for a merge that stopped on
+ * conflicts and 2 for command line usage errors.
+ */
+#[derive(Debug)]
+pub enum ArcError {
+    Io(String, io::Error),
+    NotARepo(String),
+    RepoExists(String),
+    Corrupt(String),
+    UnknownRevision(String),
+    AmbiguousRevision(String, Vec<RevID>),
+    BadRevision(String),
+    NoRevisions,
+    FileNotFound(String),
+    NotInRevision(String, RevID),
+    MergeInProgress,
+    NoMergeInProgress,
+    UnresolvedConflicts(Vec<String>),
+    NotInConflict(String),
+    EmptyMessage,
+    NoCommonAncestor(RevID, RevID),
+    InvalidName(String),
+    NameTaken(String),
+    NoSuchBranch(String),
+    NoSuchTag(String),
+    ActiveBranch(String),
+    Editor(String),
+    PushConflict,
+    BadArgument(String),
+    Ignored(String),
+    NotTracked(String),
+    DirtyFiles(Vec<String>),
+    CarryConflict(Vec<String>, RevID),
+    NoSuchStash(usize),
+    BadPatch(String),
+}
+
+pub type Result<T> = std::result::Result<T, ArcError>;
+
+impl ArcError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ArcError::Io(..) => 3,
+            ArcError::NotARepo(_) => 4,
+            ArcError::RepoExists(_) => 5,
+            ArcError::Corrupt(_) => 6,
+            ArcError::UnknownRevision(_) => 7,
+            ArcError::AmbiguousRevision(..) => 8,
+            ArcError::BadRevision(_) => 9,
+            ArcError::NoRevisions => 10,
+            ArcError::FileNotFound(_) => 11,
+            ArcError::NotInRevision(..) => 12,
+            ArcError::MergeInProgress => 13,
+            ArcError::NoMergeInProgress => 14,
+            ArcError::UnresolvedConflicts(_) => 15,
+            ArcError::NotInConflict(_) => 16,
+            ArcError::EmptyMessage => 17,
+            ArcError::NoCommonAncestor(..) => 18,
+            ArcError::InvalidName(_) => 19,
+            ArcError::NameTaken(_) => 20,
+            ArcError::NoSuchBranch(_) => 21,
+            ArcError::NoSuchTag(_) => 22,
+            ArcError::ActiveBranch(_) => 23,
+            ArcError::Editor(_) => 24,
+            ArcError::PushConflict => 25,
+            ArcError::BadArgument(_) => 26,
+            ArcError::Ignored(_) => 27,
+            ArcError::NotTracked(_) => 28,
+            ArcError::DirtyFiles(_) => 29,
+            ArcError::CarryConflict(..) => 30,
+            ArcError::NoSuchStash(_) => 31,
+            ArcError::BadPatch(_) => 32,
+        }
+    }
+    
+    // For map_err on std::io results, naming the path that was involved
+    pub fn io<P: AsRef<Path>>(path: P) -> impl FnOnce(io::Error) -> ArcError {
+        let path = path.as_ref().to_string_lossy().to_string();
+        move |e| ArcError::Io(path, e)
+    }
+    
+    // For map_err on serde results, naming the file that was being read
+    pub fn corrupt(file: &str) -> impl FnOnce(serde_json::Error) -> ArcError + '_ {
+        move |e| ArcError::Corrupt(format!("bad {} ({})", file, e))
+    }
+}
+
+impl fmt::Display for ArcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArcError::Io(path, e) => write!(f, "{}: {}", path, e),
+            ArcError::NotARepo(path) => write!(f, "not an arc repository: {}", path),
+            ArcError::RepoExists(path) => write!(f, "repository already initialized @ {}", path),
+            ArcError::Corrupt(what) => write!(f, "corrupt repository: {}", what),
+            ArcError::UnknownRevision(spec) => write!(f, "unknown revision, branch or tag: {}", spec),
+            ArcError::AmbiguousRevision(prefix, ids) => {
+                let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+                write!(f, "ambiguous revision prefix {}, it matches: {}", prefix, ids.join(", "))
+            },
+            ArcError::BadRevision(why) => write!(f, "bad revision expression: {}", why),
+            ArcError::NoRevisions => write!(f, "no revisions yet"),
+            ArcError::FileNotFound(path) => write!(f, "file doesn't exist: {}", path),
+            ArcError::NotInRevision(path, id) => write!(f, "{} is not in revision {}", path, id),
+            ArcError::MergeInProgress => write!(f, "merge in progress, use arc merge --continue or --abort"),
+            ArcError::NoMergeInProgress => write!(f, "no merge in progress"),
+            ArcError::UnresolvedConflicts(files) => write!(f, "unresolved conflicts in: {}", files.join(", ")),
+            ArcError::NotInConflict(path) => write!(f, "{} is not in conflict", path),
+            ArcError::EmptyMessage => write!(f, "aborting commit due to empty commit message"),
+            ArcError::NoCommonAncestor(a, b) => write!(f, "{} and {} have no common ancestor", a, b),
+            ArcError::InvalidName(name) => write!(f, "invalid branch or tag name: {}", name),
+            ArcError::NameTaken(name) => write!(f, "a branch or tag named {} already exists", name),
+            ArcError::NoSuchBranch(name) => write!(f, "branch doesn't exist: {}", name),
+            ArcError::NoSuchTag(name) => write!(f, "tag doesn't exist: {}", name),
+            ArcError::ActiveBranch(name) => write!(f, "can't delete the active branch {}", name),
+            ArcError::Editor(why) => write!(f, "editor failed: {}", why),
+            ArcError::PushConflict => write!(f, "push would conflict with upstream, pull and merge first"),
+            ArcError::BadArgument(why) => write!(f, "{}", why),
+            ArcError::Ignored(path) => write!(f, "{} is ignored by .arcignore, use --force to add it anyway", path),
+            ArcError::NotTracked(path) => write!(f, "not tracked: {}", path),
+            ArcError::DirtyFiles(files) => write!(f, "uncommitted changes would be lost in: {}; use --force to discard them", files.join(", ")),
+            ArcError::CarryConflict(files, id) => write!(f, "local changes to {} conflict with {}; commit them or use --force to discard them", files.join(", "), id),
+            ArcError::NoSuchStash(n) => write!(f, "no stash@{{{}}}", n),
+            ArcError::BadPatch(why) => write!(f, "bad patch: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for ArcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ArcError::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
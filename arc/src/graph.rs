@@ -0,0 +1,146 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+use crate::mach;
+use crate::revid::RevID;
+use crate::error::{ArcError, Result};
+
+/*
+ * Commit-graph index kept in .arc/commit-graph.json
+ *
+ * Stores the parents and generation number of every revision so history
+ * can be walked without opening each revision's rev.json. A revision's
+ * generation is one more than the highest generation of its parents (roots
+ * are 1), so a revision can never reach another with a higher generation.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GraphEntry {
+    rev_id: RevID,
+    parent_trunk: RevID,
+    parent_other: RevID,
+    generation: u64,
+}
+
+/// Parents and generation of every revision in the repo
+pub struct CommitGraph {
+    entries: HashMap<RevID, GraphEntry>,
+    order: Vec<RevID>,
+}
+
+impl CommitGraph {
+    /// Whether the revision has been added
+    pub fn contains(&self, rev_id: &RevID) -> bool {
+        self.entries.contains_key(rev_id)
+    }
+    
+    /// The revision's generation, 0 if it hasn't been added
+    pub fn get_generation(&self, rev_id: &RevID) -> u64 {
+        self.entries.get(rev_id).map_or(0, |e| e.generation)
+    }
+    
+    /// Non-empty parents, trunk parent first
+    pub fn get_parents(&self, rev_id: &RevID) -> Vec<RevID> {
+        let mut parents = Vec::new();
+        if let Some(e) = self.entries.get(rev_id) {
+            if !e.parent_trunk.is_empty() {
+                parents.push(e.parent_trunk);
+            }
+            if !e.parent_other.is_empty() {
+                parents.push(e.parent_other);
+            }
+        }
+        parents
+    }
+    
+    /// Parents have to be added before their children, a missing one would
+    /// leave the revision with a wrong generation
+    pub fn add(&mut self, rev_id: &RevID, parent_trunk: &RevID, parent_other: &RevID) -> Result<()> {
+        if self.contains(rev_id) {
+            return Ok(());
+        }
+        for parent in &[parent_trunk, parent_other] {
+            if !parent.is_empty() && !self.contains(parent) {
+                return Err(ArcError::Corrupt(format!("revision {} is missing its parent {}", rev_id, parent)));
+            }
+        }
+        let generation = 1 + self.get_generation(parent_trunk).max(self.get_generation(parent_other));
+        self.entries.insert(*rev_id, GraphEntry {
+            rev_id: *rev_id,
+            parent_trunk: *parent_trunk,
+            parent_other: *parent_other,
+            generation,
+        });
+        self.order.push(*rev_id);
+        Ok(())
+    }
+    
+    /// Walks parents from from_id, never descending below target_id's generation
+    pub fn can_reach(&self, from_id: &RevID, target_id: &RevID) -> bool {
+        let target_gen = self.get_generation(target_id);
+        let mut seen = HashSet::new();
+        let mut stack = vec![*from_id];
+        while let Some(id) = stack.pop() {
+            if id == *target_id {
+                return true;
+            }
+            if id.is_empty() || self.get_generation(&id) <= target_gen || !seen.insert(id) {
+                continue;
+            }
+            stack.extend(self.get_parents(&id));
+        }
+        false
+    }
+    
+    /// Every revision reachable from the given ones, themselves included
+    pub fn find_ancestors(&self, from_ids: &[RevID]) -> HashSet<RevID> {
+        let mut ancestors = HashSet::new();
+        let mut stack: Vec<RevID> = from_ids.to_vec();
+        while let Some(id) = stack.pop() {
+            if id.is_empty() || !ancestors.insert(id) {
+                continue;
+            }
+            stack.extend(self.get_parents(&id));
+        }
+        ancestors
+    }
+    
+    /// Revisions that no other revision names as a parent
+    pub fn find_heads(&self) -> Vec<RevID> {
+        let mut parents = HashSet::new();
+        for id in &self.order {
+            parents.extend(self.get_parents(id));
+        }
+        self.order.iter().filter(|id| !parents.contains(id)).cloned().collect()
+    }
+    
+    /// Writes commit-graph.json into the given .arc dir
+    pub fn save(&self, arc_path: &String) -> Result<()> {
+        let entries: Vec<&GraphEntry> = self.order.iter().map(|id| &self.entries[id]).collect();
+        let serialized = serde_json::to_string(&entries).unwrap();
+        mach::write_string(arc_path, &String::from("commit-graph.json"), &serialized)
+    }
+}
+
+/// An empty graph
+pub fn new() -> CommitGraph {
+    CommitGraph {
+        entries: HashMap::new(),
+        order: Vec::new(),
+    }
+}
+
+/// Reads commit-graph.json from the given .arc dir, None if there isn't one
+pub fn open(arc_path: &String) -> Result<Option<CommitGraph>> {
+    if !mach::check_path(&mach::join_paths(arc_path, &String::from("commit-graph.json"))) {
+        return Ok(None);
+    }
+    
+    let json = mach::read_line(arc_path, &String::from("commit-graph.json"))?;
+    let entries: Vec<GraphEntry> = serde_json::from_str(&json).map_err(ArcError::corrupt("commit-graph.json"))?;
+    
+    let mut g = new();
+    for e in entries {
+        g.order.push(e.rev_id);
+        g.entries.insert(e.rev_id, e);
+    }
+    Ok(Some(g))
+}
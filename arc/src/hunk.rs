@@ -0,0 +1,211 @@
+use crate::mach;
+use crate::repo;
+use crate::store;
+use crate::error::{ArcError, Result};
+
+/*
+ * Picking hunks for a commit
+ *
+ * A file's changes since it was staged are cut into hunks the way `arc diff`
+ * shows them. Each hunk picked is written back out as a one-hunk patch and
+ * applied to the staged content with diffy, which finds where it goes by its
+ * context lines; a patch file of picked hunks is applied the same way to the
+ * current revision's content, one file at a time.
+ */
+
+const NO_NEWLINE: &str = "\\ No newline at end of file\n";
+
+#[derive(Clone)]
+pub struct Hunk {
+    // 1-based first line on each side, as in the "@@" header
+    old_start: usize,
+    new_start: usize,
+    // One " ", "-" or "+" line each, ending in '\n' and followed by the
+    // no-newline marker when the file ends without one
+    lines: Vec<String>,
+}
+
+impl Hunk {
+    fn counts(&self) -> (usize, usize) {
+        let old = self.lines.iter().filter(|l| !l.starts_with('+')).count();
+        let new = self.lines.iter().filter(|l| !l.starts_with('-')).count();
+        (old, new)
+    }
+    
+    fn header(&self, new_start: usize) -> String {
+        let (old, new) = self.counts();
+        format!("@@ -{},{} +{},{} @@\n", self.old_start, old, new_start, new)
+    }
+    
+    // The lines the hunk expects in the old file
+    fn pre_image(&self) -> Vec<&str> {
+        self.lines.iter().filter(|l| !l.starts_with('+')).map(|l| &l[1..]).collect()
+    }
+    
+    pub fn text(&self) -> String {
+        self.header(self.new_start) + &self.lines.concat()
+    }
+    
+    /// Cuts the hunk at each run of context lines between its changes, or
+    /// None if it has only one run of changes
+    pub fn split(&self) -> Option<Vec<Hunk>> {
+        let is_change = |l: &String| !l.starts_with(' ');
+        let mut cuts = Vec::new();
+        for i in 1..self.lines.len() {
+            if !is_change(&self.lines[i]) && is_change(&self.lines[i - 1]) && self.lines[i..].iter().any(is_change) {
+                cuts.push(i);
+            }
+        }
+        if cuts.is_empty() {
+            return None;
+        }
+        cuts.push(self.lines.len());
+        
+        let mut parts = Vec::new();
+        let (mut old_start, mut new_start, mut from) = (self.old_start, self.new_start, 0);
+        for to in cuts {
+            let part = Hunk { old_start, new_start, lines: self.lines[from..to].to_vec() };
+            let (old, new) = part.counts();
+            old_start += old;
+            new_start += new;
+            from = to;
+            parts.push(part);
+        }
+        Some(parts)
+    }
+    
+    /// Reads back a hunk edited by hand. Lines starting with '#' and the
+    /// "@@" header are skipped, and the ' ' and '-' lines must still be the
+    /// ones the hunk had.
+    pub fn edit(&self, edited: &str) -> Result<Hunk> {
+        let mut lines: Vec<String> = Vec::new();
+        for l in edited.split_inclusive('\n') {
+            if l.starts_with('#') || l.starts_with("@@") {
+                continue;
+            }
+            let l = if l.ends_with('\n') { l.to_string() } else { format!("{}\n", l) };
+            if l.starts_with('\\') {
+                match lines.last_mut() {
+                    Some(prev) => prev.push_str(NO_NEWLINE),
+                    None => return Err(ArcError::BadPatch("no-newline marker before any line".to_string())),
+                }
+            } else if l == "\n" {
+                // Editors often strip the space off an empty context line
+                lines.push(" \n".to_string());
+            } else if l.starts_with([' ', '-', '+']) {
+                lines.push(l);
+            } else {
+                return Err(ArcError::BadPatch(format!("unexpected line in edited hunk: {}", l.trim_end())));
+            }
+        }
+        
+        let hunk = Hunk { old_start: self.old_start, new_start: self.new_start, lines };
+        if hunk.pre_image() != self.pre_image() {
+            return Err(ArcError::BadPatch("the edited hunk changed the lines it expects in the file".to_string()));
+        }
+        Ok(hunk)
+    }
+}
+
+pub struct FileHunks {
+    pub path: String,
+    // The file's staged content
+    pub old: String,
+    pub hunks: Vec<Hunk>,
+}
+
+fn find_hunks(old: &str, new: &str) -> Vec<Hunk> {
+    let patch = diffy::create_patch(old, new);
+    let mut hunks = Vec::new();
+    for h in patch.hunks() {
+        let mut lines = Vec::new();
+        for l in h.lines() {
+            let (prefix, text) = match l {
+                diffy::Line::Context(t) => (' ', t),
+                diffy::Line::Delete(t) => ('-', t),
+                diffy::Line::Insert(t) => ('+', t),
+            };
+            if text.ends_with('\n') {
+                lines.push(format!("{}{}", prefix, text));
+            } else {
+                lines.push(format!("{}{}\n{}", prefix, text, NO_NEWLINE));
+            }
+        }
+        hunks.push(Hunk { old_start: h.old_range().start(), new_start: h.new_range().start(), lines });
+    }
+    hunks
+}
+
+// Tracked text files whose working-tree content differs from what is staged
+// for them
+pub fn find_changes(repo: &repo::Repo) -> Result<Vec<FileHunks>> {
+    let mut changes = Vec::new();
+    for (f, h) in repo.get_index() {
+        if !mach::check_path(&mach::join_paths(&repo.root_path, f)) || h == &store::hash_file(&repo.root_path, f)? {
+            continue;
+        }
+        let old = store::get(&repo.arc_path, h)?;
+        let new = mach::read_bytes(&repo.root_path, f)?;
+        if let (Ok(old), Ok(new)) = (String::from_utf8(old), String::from_utf8(new)) {
+            let hunks = find_hunks(&old, &new);
+            changes.push(FileHunks { path: f.clone(), old, hunks });
+        }
+    }
+    Ok(changes)
+}
+
+// The old content with the hunks, given in order, applied
+pub fn apply_hunks(old: &str, hunks: &[Hunk]) -> Result<String> {
+    let mut image = old.to_string();
+    let mut shift: isize = 0;
+    for h in hunks {
+        // Hunks picked before this one moved its lines by their size
+        let new_start = (h.old_start as isize + shift).max(0) as usize;
+        let text = h.header(new_start) + &h.lines.concat();
+        image = apply_text(&image, &text)?;
+        let (old, new) = h.counts();
+        shift += new as isize - old as isize;
+    }
+    Ok(image)
+}
+
+fn apply_text(old: &str, patch_text: &str) -> Result<String> {
+    let patch = diffy::Patch::from_str(patch_text).map_err(|e| ArcError::BadPatch(e.to_string()))?;
+    diffy::apply(old, &patch).map_err(|e| ArcError::BadPatch(e.to_string()))
+}
+
+// Applies a patch, such as `arc diff HEAD` prints, to the current revision's
+// version of each tracked file it names, giving each file's new content
+pub fn apply_patch(repo: &repo::Repo, patch_text: &str) -> Result<Vec<(String, String)>> {
+    let head = repo.head_rev()?;
+    
+    // Every "diff " line starts the next file's section
+    let mut sections: Vec<String> = Vec::new();
+    for l in patch_text.split_inclusive('\n') {
+        if l.starts_with("diff ") || sections.is_empty() {
+            sections.push(String::new());
+        }
+        sections.last_mut().unwrap().push_str(l);
+    }
+    
+    let mut applied = Vec::new();
+    for section in &sections {
+        let patch = diffy::Patch::from_str(section).map_err(|e| ArcError::BadPatch(e.to_string()))?;
+        let name = match (patch.original(), patch.modified()) {
+            (_, Some(m)) if m != "/dev/null" => m.strip_prefix("b/").unwrap_or(m).to_string(),
+            (Some(o), _) if o != "/dev/null" => return Err(ArcError::BadPatch(format!("{} is deleted; use arc remove", o.strip_prefix("a/").unwrap_or(o)))),
+            _ => return Err(ArcError::BadPatch("a file's '---' and '+++' lines are missing".to_string())),
+        };
+        if repo.get_staged_blob(&name).is_none() {
+            return Err(ArcError::NotTracked(name));
+        }
+        
+        let old = match &head {
+            Some(r) => r.read_file(&name)?.unwrap_or_default(),
+            None => String::new(),
+        };
+        let new = apply_text(&old, section)?;
+        applied.push((name, new));
+    }
+    Ok(applied)
+}
@@ -0,0 +1,198 @@
+use crate::mach;
+use crate::error::Result;
+
+/*
+ * Ignore rules from .arcignore files
+ *
+ * Every directory can have a .arcignore with one gitignore-style pattern per
+ * line. Blank lines and lines starting with '#' are skipped, a leading '!'
+ * re-includes what an earlier pattern excluded, and a trailing '/' only
+ * matches directories. A pattern with a '/' in it is matched against the
+ * path relative to its .arcignore; one without is matched against the name
+ * at any depth below it. The last matching pattern wins, and patterns from
+ * deeper .arcignore files come after those from shallower ones.
+ */
+struct Pattern {
+    // Directory of the .arcignore the pattern came from, "" for the root
+    base: String,
+    glob: String,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl Pattern {
+    fn parse(base: &str, line: &str) -> Option<Pattern> {
+        let mut p = line.trim_end();
+        if p.is_empty() || p.starts_with('#') {
+            return None;
+        }
+        
+        // A '\' keeps a leading '!' or '#' literal
+        let negated = p.starts_with('!');
+        if negated || p.starts_with("\\!") || p.starts_with("\\#") {
+            p = &p[1..];
+        }
+        let dir_only = p.ends_with('/');
+        let p = p.trim_end_matches('/');
+        let anchored = p.contains('/');
+        let p = p.trim_start_matches('/');
+        if p.is_empty() {
+            return None;
+        }
+        
+        Some(Pattern {
+            base: base.to_string(),
+            glob: p.to_string(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+    
+    fn matches(&self, rel_path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let rest = if self.base.is_empty() {
+            rel_path
+        } else {
+            match rel_path.strip_prefix(&self.base).and_then(|r| r.strip_prefix('/')) {
+                Some(r) => r,
+                None => return false,
+            }
+        };
+        if self.anchored {
+            glob_match(&self.glob, rest)
+        } else {
+            glob_match(&self.glob, rest.rsplit('/').next().unwrap_or(rest))
+        }
+    }
+}
+
+pub struct Ignore {
+    patterns: Vec<Pattern>,
+}
+
+impl Ignore {
+    // A path inside an ignored directory stays ignored, even if a later
+    // pattern would re-include it
+    pub fn is_ignored(&self, rel_path: &str, is_dir: bool) -> bool {
+        let parts: Vec<&str> = rel_path.split('/').collect();
+        for i in 1..parts.len() {
+            if self.matches(&parts[..i].join("/"), true) {
+                return true;
+            }
+        }
+        self.matches(rel_path, is_dir)
+    }
+    
+    fn matches(&self, rel_path: &str, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for p in &self.patterns {
+            if p.matches(rel_path, is_dir) {
+                ignored = !p.negated;
+            }
+        }
+        ignored
+    }
+}
+
+// Reads every .arcignore in the working tree
+pub fn load(root_path: &String) -> Result<Ignore> {
+    let mut files: Vec<String> = mach::list_files(root_path)?
+        .into_iter()
+        .filter(|f| f == ".arcignore" || f.ends_with("/.arcignore"))
+        .collect();
+    files.sort_by_key(|f| f.matches('/').count());
+    
+    let mut patterns = Vec::new();
+    for f in &files {
+        let base = f.strip_suffix(".arcignore").unwrap_or("").trim_end_matches('/');
+        for line in mach::read_line(root_path, f)?.lines() {
+            patterns.extend(Pattern::parse(base, line));
+        }
+    }
+    Ok(Ignore { patterns })
+}
+
+/*
+ * Globs
+ *
+ * '*' matches any run of characters but '/', '?' any one character but '/',
+ * "[...]" one character of a set (with ranges, and '!' or '^' to negate it),
+ * and '**' any number of whole directories. A '\' makes the next character
+ * literal.
+ */
+pub fn is_glob(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+pub fn glob_match(glob: &str, path: &str) -> bool {
+    let g: Vec<char> = glob.chars().collect();
+    let p: Vec<char> = path.chars().collect();
+    match_from(&g, &p)
+}
+
+fn match_from(g: &[char], p: &[char]) -> bool {
+    match g.first() {
+        None => p.is_empty(),
+        Some('*') if g.get(1) == Some(&'*') => {
+            if g.get(2) == Some(&'/') {
+                // "**/" matches zero or more leading directories
+                match_from(&g[3..], p) || (0..p.len()).any(|i| p[i] == '/' && match_from(&g[3..], &p[i + 1..]))
+            } else {
+                (0..=p.len()).any(|i| match_from(&g[2..], &p[i..]))
+            }
+        },
+        Some('*') => {
+            for i in 0..=p.len() {
+                if match_from(&g[1..], &p[i..]) {
+                    return true;
+                }
+                if i < p.len() && p[i] == '/' {
+                    break;
+                }
+            }
+            false
+        },
+        Some('?') => !p.is_empty() && p[0] != '/' && match_from(&g[1..], &p[1..]),
+        Some('[') => match match_class(&g[1..], p.first()) {
+            Some((true, len)) => match_from(&g[len + 1..], &p[1..]),
+            Some((false, _)) => false,
+            // No closing ']', so the '[' is just a character
+            None => p.first() == Some(&'[') && match_from(&g[1..], &p[1..]),
+        },
+        Some('\\') if g.len() > 1 => p.first() == Some(&g[1]) && match_from(&g[2..], &p[1..]),
+        Some(c) => p.first() == Some(c) && match_from(&g[1..], &p[1..]),
+    }
+}
+
+// Matches c against the set that g starts with (just after the '['),
+// returning whether it matched and the length of the set up to its ']'
+fn match_class(g: &[char], c: Option<&char>) -> Option<(bool, usize)> {
+    let negated = g.first() == Some(&'!') || g.first() == Some(&'^');
+    let mut i = if negated { 1 } else { 0 };
+    let mut found = false;
+    let mut first = true;
+    while i < g.len() {
+        if g[i] == ']' && !first {
+            let matched = match c {
+                Some(&c) => c != '/' && found != negated,
+                None => false,
+            };
+            return Some((matched, i + 1));
+        }
+        first = false;
+        if i + 2 < g.len() && g[i + 1] == '-' && g[i + 2] != ']' {
+            if let Some(&c) = c {
+                found |= g[i] <= c && c <= g[i + 2];
+            }
+            i += 3;
+        } else {
+            found |= c == Some(&g[i]);
+            i += 1;
+        }
+    }
+    None
+}
@@ -0,0 +1,26 @@
+use std::collections::BTreeMap;
+use crate::mach;
+use crate::error::{ArcError, Result};
+
+/*
+ * The staging area, kept in .arc/index
+ *
+ * Maps every tracked file to the blob the next commit will have for it.
+ * Adding a file puts its working-tree content in the store and here;
+ * committing turns the whole map into the new revision. After a checkout it
+ * holds exactly the checked out revision's files.
+ */
+
+// None for repos from before the index existed
+pub fn load(arc_path: &String) -> Result<Option<BTreeMap<String, String>>> {
+    if !mach::check_path(&mach::join_paths(arc_path, &String::from("index"))) {
+        return Ok(None);
+    }
+    let json = mach::read_line(arc_path, &String::from("index"))?;
+    serde_json::from_str(&json).map(Some).map_err(ArcError::corrupt("index"))
+}
+
+pub fn save(arc_path: &String, index: &BTreeMap<String, String>) -> Result<()> {
+    let serialized = serde_json::to_string(index).unwrap();
+    mach::write_string(arc_path, &String::from("index"), &serialized)
+}
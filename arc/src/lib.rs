@@ -0,0 +1,60 @@
+//! arc, a small distributed version control system.
+//!
+//! The library holds everything a repository can do; the `arc` binary is a
+//! thin command-line client on top of it. Nothing in here prints: operations
+//! hand back what they did (the new [`Rev`], a [`MergeOutcome`], a
+//! [`SyncOutcome`]) and fail with an [`ArcError`].
+//!
+//! ```no_run
+//! use arc::{repo, LocalChanges, MergeOutcome};
+//!
+//! let mut r = repo::open(&"/path/to/repo".to_string()).unwrap();
+//! r.add_file(&"notes.txt".to_string(), false).unwrap();
+//! let rev = r.commit("Add notes").unwrap();
+//!
+//! match r.merge(&rev.get_id_str(), &"feature".to_string(), LocalChanges::Refuse).unwrap() {
+//!     MergeOutcome::Conflicts { files, .. } => println!("conflicts in {:?}", files),
+//!     outcome => { r.advance(&outcome.rev().unwrap().get_id_str(), LocalChanges::Refuse).unwrap(); },
+//! }
+//! ```
+
+/// File system, path and environment helpers
+pub mod mach;
+/// Revision IDs, derived from each revision's content
+pub mod revid;
+/// A repository and everything that can be done to it
+pub mod repo;
+/// A single revision and its files
+pub mod rev;
+/// Three-way merges, merge bases and the state of a stopped merge
+pub mod merge;
+/// The content-addressed blob store under .arc/objects
+pub mod store;
+/// History walks and the `arc log` output
+pub mod log;
+/// What changed in the index and the working tree
+pub mod status;
+/// Unified diffs between revisions, the index and the working tree
+pub mod diff;
+/// The commit-graph index of parents and generations
+pub mod graph;
+/// Branches and tags under .arc/refs
+pub mod refs;
+/// Revision expressions such as `HEAD~2` or `main^2`
+pub mod revspec;
+/// .arcignore patterns
+pub mod ignore;
+/// The stack of stashed changes
+pub mod stash;
+/// The staging area between the working tree and a commit
+pub mod index;
+/// Picking hunks of a change to commit
+pub mod hunk;
+/// The errors arc can fail with and their exit codes
+pub mod error;
+
+pub use error::{ArcError, Result};
+pub use merge::MergeOutcome;
+pub use repo::{LocalChanges, Repo, SyncOutcome};
+pub use rev::Rev;
+pub use revid::RevID;
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use crate::diff;
+use crate::mach;
+use crate::repo;
+use crate::rev;
+use crate::revid::RevID;
+use crate::error::Result;
+
+/*
+ * Topological walk
+ */
+fn get_parents(rev: &rev::Rev) -> Vec<RevID> {
+    let mut parents = Vec::new();
+    if !rev.get_parent_trunk_id().is_empty() {
+        parents.push(*rev.get_parent_trunk_id());
+    }
+    if !rev.get_parent_other_id().is_empty() {
+        parents.push(*rev.get_parent_other_id());
+    }
+    parents
+}
+
+// Children always come before their parents; among the revisions that are
+// ready to be shown, the most recent one goes first
+pub fn topo_order(repo: &repo::Repo, start_id: &RevID) -> Result<Vec<rev::Rev>> {
+    let mut revs: HashMap<RevID, rev::Rev> = HashMap::new();
+    let mut children: HashMap<RevID, usize> = HashMap::new();
+    let mut stack = vec![*start_id];
+    while let Some(id) = stack.pop() {
+        if id.is_empty() || revs.contains_key(&id) {
+            continue;
+        }
+        let r = rev::open(repo, &id)?;
+        for p in get_parents(&r) {
+            *children.entry(p).or_insert(0) += 1;
+            stack.push(p);
+        }
+        revs.insert(id, r);
+    }
+    
+    let mut order = Vec::new();
+    let mut ready = vec![*start_id];
+    while !ready.is_empty() {
+        let mut best = 0;
+        for i in 1..ready.len() {
+            let (a, b) = (&revs[&ready[i]], &revs[&ready[best]]);
+            if (a.get_timestamp(), a.get_id_str()) > (b.get_timestamp(), b.get_id_str()) {
+                best = i;
+            }
+        }
+        let id = ready.remove(best);
+        let r = revs.remove(&id).unwrap();
+        for p in get_parents(&r) {
+            let c = children.get_mut(&p).unwrap();
+            *c -= 1;
+            if *c == 0 {
+                ready.push(p);
+            }
+        }
+        order.push(r);
+    }
+    Ok(order)
+}
+
+/*
+ * Path filtering
+ */
+fn changed_from(rev: &rev::Rev, parent: Option<&rev::Rev>, paths: &[String]) -> bool {
+    let mut files = rev.get_files();
+    if let Some(p) = parent {
+        files.extend(p.get_files());
+    }
+    files.iter().filter(|f| diff::matches_paths(f, paths)).any(|f| {
+        let parent_blob = parent.and_then(|p| p.get_blob(f));
+        rev.get_blob(f) != parent_blob
+    })
+}
+
+// A revision touches the paths if they differ from every one of its parents
+pub fn touches_paths(repo: &repo::Repo, rev: &rev::Rev, paths: &[String]) -> Result<bool> {
+    let parents = get_parents(rev);
+    if parents.is_empty() {
+        return Ok(changed_from(rev, None, paths));
+    }
+    for p in &parents {
+        if !changed_from(rev, Some(&rev::open(repo, p)?), paths) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/*
+ * ASCII graph
+ *
+ * Each column is a line of history waiting for the revision it names.
+ */
+pub struct Graph {
+    columns: Vec<RevID>,
+}
+
+impl Graph {
+    pub fn width(&self) -> usize {
+        self.columns.len() * 2
+    }
+    
+    pub fn padding(&self) -> String {
+        let mut row = String::new();
+        for _ in &self.columns {
+            row.push_str("| ");
+        }
+        row.trim_end().to_string()
+    }
+    
+    // Returns the rows drawn for this revision: the node itself followed by
+    // any rows that open a merge or collapse lines that met again
+    pub fn next(&mut self, id: &RevID, parents: &[RevID]) -> Vec<String> {
+        let mut rows = Vec::new();
+        let col = match self.columns.iter().position(|c| c == id) {
+            Some(c) => c,
+            None => {
+                self.columns.push(*id);
+                self.columns.len() - 1
+            }
+        };
+        
+        let mut node = String::new();
+        for i in 0..self.columns.len() {
+            node.push_str(if i == col { "* " } else { "| " });
+        }
+        rows.push(node);
+        
+        if parents.is_empty() {
+            self.columns.remove(col);
+        } else {
+            self.columns[col] = parents[0];
+        }
+        // A second parent that already has a line of its own joins it through
+        // the collapse rows below instead of opening a new line
+        if parents.len() > 1 && !self.columns.contains(&parents[1]) {
+            let mut row = vec![' '; self.columns.len() * 2 + 2];
+            for i in 0..self.columns.len() {
+                if i <= col {
+                    row[i * 2] = '|';
+                } else {
+                    row[i * 2 + 1] = '\\';
+                }
+            }
+            row[col * 2 + 1] = '\\';
+            rows.push(row.into_iter().collect());
+            self.columns.insert(col + 1, parents[1]);
+        }
+        
+        while let Some(j) = self.find_duplicate() {
+            let mut row = vec![' '; self.columns.len() * 2];
+            for i in 0..self.columns.len() {
+                if i < j {
+                    row[i * 2] = '|';
+                } else {
+                    row[i * 2 - 1] = '/';
+                }
+            }
+            rows.push(row.into_iter().collect());
+            self.columns.remove(j);
+        }
+        rows
+    }
+    
+    fn find_duplicate(&self) -> Option<usize> {
+        (1..self.columns.len()).rev().find(|&j| self.columns[..j].contains(&self.columns[j]))
+    }
+}
+
+/*
+ * Output
+ */
+fn describe(rev: &rev::Rev, oneline: bool) -> Vec<String> {
+    if oneline {
+        return vec![format!("{} {}", rev.get_id(), rev.get_summary())];
+    }
+    
+    let mut lines = vec![format!("revision {}", rev.get_id())];
+    if !rev.get_parent_other_id().is_empty() {
+        lines.push(format!("Merge: {} {}", rev.get_parent_trunk_id(), rev.get_parent_other_id()));
+    }
+    lines.push(format!("Author: {}", rev.get_author()));
+    lines.push(format!("Date:   {}", mach::format_timestamp(rev.get_timestamp())));
+    lines.push(String::new());
+    for l in rev.get_message().lines() {
+        lines.push(format!("    {}", l));
+    }
+    lines.push(String::new());
+    lines
+}
+
+// The lines of the log starting at start_id; an empty start_id, as in a
+// repo without revisions, has no lines
+pub fn format_log(repo: &repo::Repo, start_id: &RevID, limit: Option<usize>, paths: &[String], oneline: bool) -> Result<Vec<String>> {
+    let mut out = Vec::new();
+    if start_id.is_empty() {
+        return Ok(out);
+    }
+    // A graph of a filtered history would have gaps, so only draw it for the full history
+    let mut graph = if paths.is_empty() { Some(Graph { columns: Vec::new() }) } else { None };
+    let mut shown = 0;
+    for r in topo_order(repo, start_id)? {
+        if limit.is_some_and(|n| shown >= n) {
+            break;
+        }
+        
+        let text = if paths.is_empty() || touches_paths(repo, &r, paths)? {
+            shown += 1;
+            describe(&r, oneline)
+        } else {
+            Vec::new()
+        };
+        
+        match graph.as_mut() {
+            Some(g) => {
+                let rows: Vec<String> = g.next(r.get_id(), &get_parents(&r)).iter().map(|row| row.trim_end().to_string()).collect();
+                let width = rows.iter().map(|row| row.len()).max().unwrap_or(0).max(g.width().saturating_sub(1));
+                for i in 0..rows.len().max(text.len()) {
+                    let row = if i < rows.len() { rows[i].clone() } else { g.padding() };
+                    let line = if i < text.len() { text[i].as_str() } else { "" };
+                    out.push(format!("{:width$} {}", row, line, width = width).trim_end().to_string());
+                }
+            },
+            None => out.extend(text),
+        }
+    }
+    Ok(out)
+}
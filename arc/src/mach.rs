@@ -7,11 +7,58 @@ use std::io::prelude::*;
 use std::fs::File;
 use std::io::Write;
 //use std::io::{Write, BufReader, BufRead};
-use std::path::PathBuf;
+use std::path::{Component, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::error::{ArcError, Result};
+
+pub fn get_cwd() -> Result<String> { // cwd = current working directory
+    let cwd = env::current_dir().map_err(ArcError::io("."))?;
+    Ok(cwd.to_string_lossy().to_string())
+}
+
+pub fn get_author() -> String {
+    for var in &["ARC_AUTHOR", "USER", "USERNAME"] {
+        if let Ok(name) = env::var(var) {
+            if !name.trim().is_empty() {
+                return name.trim().to_string();
+            }
+        }
+    }
+    "unknown".to_string()
+}
 
-pub fn get_cwd() -> String { // cwd = current working directory
-    let cwd = env::current_dir().unwrap().into_os_string().into_string().unwrap();
-    cwd
+pub fn get_timestamp() -> u64 {
+    // A clock set before 1970 just dates everything at the epoch
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+pub fn format_timestamp(secs: u64) -> String {
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+pub fn run_editor(file_path: &str) -> Result<()> {
+    let editor = env::var("ARC_EDITOR").or_else(|_| env::var("EDITOR")).unwrap_or_else(|_| "vi".to_string());
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+    let status = Command::new(program).args(parts).arg(file_path).status()
+        .map_err(|e| ArcError::Editor(format!("unable to launch {} ({})", program, e)))?;
+    if !status.success() {
+        return Err(ArcError::Editor(format!("{} exited with {}", program, status)));
+    }
+    Ok(())
 }
 
 pub fn join_paths(path1: &String, path2: &String) -> String {
@@ -23,6 +70,10 @@ pub fn check_path(path: &String) -> bool {
     Path::new(path).exists()
 }
 
+pub fn check_dir(path: &String) -> bool {
+    Path::new(path).is_dir()
+}
+
 pub fn check_repo_dir(path: &String) -> bool {
     Path::new(path).join(Path::new(".arc")).exists()
 }
@@ -32,8 +83,8 @@ pub fn check_repo_dir(path: &String) -> bool {
 //    fs::create_dir_all(p).expect("Unable to create dir");
 //}
 
-pub fn create_dir_all(path: &String) {
-    fs::create_dir_all(path).expect("Unable to create dir");
+pub fn create_dir_all(path: &String) -> Result<()> {
+    fs::create_dir_all(path).map_err(ArcError::io(path))
 }
 
 //pub fn write_lines(path: &String, name: &String, lines: &Vec<String>) {
@@ -45,10 +96,10 @@ pub fn create_dir_all(path: &String) {
 //    }
 //}
 
-pub fn write_string(path: &String, name: &String, s: &String) {
+pub fn write_string(path: &String, name: &String, s: &String) -> Result<()> {
     let p = Path::new(path).join(Path::new(name));
-    let mut f = File::create(p).expect("Unable to create file");
-    f.write_all(s.as_bytes()).expect("Unable to write line");
+    let mut f = File::create(&p).map_err(ArcError::io(&p))?;
+    f.write_all(s.as_bytes()).map_err(ArcError::io(&p))
 }
 
 //pub fn read_lines(path: &String, name: &String) -> Vec<String> {
@@ -58,15 +109,29 @@ pub fn write_string(path: &String, name: &String, s: &String) {
 //    buf.lines().map(|l| l.expect("Unable to read line")).collect()
 //}
 
-pub fn read_line(path: &String, name: &String) -> String {
+pub fn read_line(path: &String, name: &String) -> Result<String> {
     let p = Path::new(path).join(Path::new(name));
-    let mut f = File::open(p).expect("Unable to open file");
+    let mut f = File::open(&p).map_err(ArcError::io(&p))?;
     let mut l = String::new();
-    f.read_to_string(&mut l).expect("Unable to read the file");
-    l
+    f.read_to_string(&mut l).map_err(ArcError::io(&p))?;
+    Ok(l)
+}
+
+pub fn write_bytes(path: &String, name: &String, data: &[u8]) -> Result<()> {
+    let p = Path::new(path).join(Path::new(name));
+    if let Some(parent) = p.parent() {
+        fs::create_dir_all(parent).map_err(ArcError::io(parent))?;
+    }
+    let mut f = File::create(&p).map_err(ArcError::io(&p))?;
+    f.write_all(data).map_err(ArcError::io(&p))
 }
 
-pub fn is_empty_path(pbuf: &PathBuf) -> bool {
+pub fn read_bytes(path: &String, name: &String) -> Result<Vec<u8>> {
+    let p = Path::new(path).join(Path::new(name));
+    fs::read(&p).map_err(ArcError::io(&p))
+}
+
+pub fn is_empty_path(pbuf: &Path) -> bool {
     // doesn't work on Windows bc assume "/" is root
     // Path::new("/") == pbuf
 
@@ -81,16 +146,31 @@ pub fn find_repo_root_path(path: &String) -> String {
         //println!("p: {}", p);
         if check_repo_dir(&p) {
             break;
-        } else {
-            pbuf.pop();
+        } else if !pbuf.pop() {
+            // Walked past the filesystem root without finding a repo
+            return "".to_string();
+        }
+    }
+    pbuf.to_string_lossy().to_string()
+}
+
+pub fn normalize_path(path: &String) -> String {
+    let mut pbuf = PathBuf::new();
+    for c in Path::new(path).components() {
+        match c {
+            Component::CurDir => {},
+            Component::ParentDir => { pbuf.pop(); },
+            _ => pbuf.push(c.as_os_str()),
         }
     }
     pbuf.to_string_lossy().to_string()
 }
 
-pub fn find_rel_path(base_path: &String, full_path: &String) -> String {
+pub fn find_rel_path(base_path: &str, full_path: &str) -> String {
     let b = full_path.starts_with(base_path);
-    if b {
+    if b && full_path.len() == base_path.len() {
+        "".to_string()
+    } else if b {
         full_path[base_path.len() + 1..].to_string()
     } else {
         "".to_string()
@@ -112,32 +192,59 @@ pub fn extract_path(filename: &String) -> String {
     pbuf.to_string_lossy().to_string()
 }
 
-pub fn copy_file(dst_path: &String, src_path: &String, f: &String) {
-    let d = join_paths(dst_path, &f);
-    let s = join_paths(src_path, &f);
+pub fn copy_file(dst_path: &String, src_path: &String, f: &String) -> Result<()> {
+    let d = join_paths(dst_path, f);
+    let s = join_paths(src_path, f);
     let d_path = extract_path(&d);
     if !check_path(&d_path) {
-        create_dir_all(&d_path);
+        create_dir_all(&d_path)?;
     }
     //println!("Copy {} -> {}", s, d);
-    fs::copy(&s, &d).expect("Unable to copy file");
+    fs::copy(&s, &d).map_err(ArcError::io(&s))?;
+    Ok(())
 }
 
-pub fn del_files(base_path: &String, files: &Vec<String>) {
-    for f in files {
-        let p = join_paths(base_path, &f);
-        if Path::new(&p).exists() {
-            //println!("Del {}", p);
-            fs::remove_file(&p).expect("Unable to delete file");
+// All files below base_path, relative to it, skipping the .arc dir
+pub fn list_files(base_path: &String) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::from(base_path)];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir).map_err(ArcError::io(&dir))? {
+            let p = entry.map_err(ArcError::io(&dir))?.path();
+            if p.file_name() == Some(ffi::OsStr::new(".arc")) {
+                continue;
+            }
+            if p.is_dir() {
+                dirs.push(p);
+            } else {
+                files.push(find_rel_path(base_path, &p.to_string_lossy()));
+            }
         }
     }
+    files.sort();
+    Ok(files)
+}
+
+pub fn del_files(base_path: &String, files: &[String]) -> Result<()> {
+    for f in files {
+        del_file(base_path, f)?;
+    }
+    Ok(())
+}
+
+pub fn del_dir(path: &String) -> Result<()> {
+    if Path::new(path).exists() {
+        fs::remove_dir_all(path).map_err(ArcError::io(path))?;
+    }
+    Ok(())
 }
 
-pub fn del_file(base_path: &String, f: &String) {
-    let p = join_paths(base_path, &f);
+pub fn del_file(base_path: &String, f: &String) -> Result<()> {
+    let p = join_paths(base_path, f);
     if Path::new(&p).exists() {
         //println!("Del {}", p);
-        fs::remove_file(&p).expect("Unable to delete file");
+        fs::remove_file(&p).map_err(ArcError::io(&p))?;
     }
+    Ok(())
 }
 
@@ -1,11 +1,12 @@
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
+use std::process;
 
 mod cmd;
-mod mach;
-mod revid;
-mod repo;
-mod rev;
-mod merge;
+
+use arc::mach;
+use arc::stash;
+use arc::{ArcError, LocalChanges};
+use cmd::{Command, DiffFormat, Staging};
 
 fn main() {
     let matches = App::new("arc")
@@ -22,6 +23,11 @@ fn main() {
                 .about("Prints a repository")
                 .arg(Arg::new("path").about("The repository user wants to print").required(false)),
         )
+        .subcommand(
+            App::new("status")
+                .about("Show staged changes and changes in the working tree that aren't staged")
+                .arg(Arg::new("porcelain").about("Machine-readable output").long("porcelain"))
+        )
         .subcommand(
             App::new("clone")
                 .about("Clone a repository")
@@ -30,41 +36,133 @@ fn main() {
         )
         .subcommand(
             App::new("add")
-                .about("Add specific files that user wants to track")
-                .arg(Arg::new("path").about("Path of the added file").required(true))
+                .about("Stage files for the next commit, tracking the ones that aren't yet")
+                .arg(Arg::new("path").about("Files, directories or globs (such as 'src/**/*.rs') to add").multiple(true).required_unless_present("all"))
+                .arg(Arg::new("all").about("Add every file in the working tree").short('A').long("all").conflicts_with("path"))
+                .arg(Arg::new("force").about("Add files even if .arcignore matches them").short('f').long("force"))
         )
         .subcommand(
             App::new("remove")
                 .about("Remove specific files from tracking list")
-                .arg(Arg::new("path").about("Path of the removed file").required(true))
+                .arg(Arg::new("path").about("Files or directories to stop tracking").multiple(true).required(true))
+                .arg(Arg::new("cached").about("Keep the files in the working tree").long("cached"))
+                .arg(Arg::new("force").about("Delete files even if they have uncommitted changes").short('f').long("force"))
         )
         .subcommand(
             App::new("heads")
                 .about("Show the current heads")
         )
+        .subcommand(
+            App::new("branch")
+                .about("List, create, delete or rename branches")
+                .arg(Arg::new("name").about("Branch to create").required(false))
+                .arg(Arg::new("rev").about("Revision the new branch points at (default: current revision)").required(false))
+                .arg(Arg::new("delete").about("Delete a branch").short('d').long("delete").takes_value(true).conflicts_with_all(&["name", "rev", "move"]))
+                .arg(Arg::new("move").about("Rename a branch").short('m').long("move").takes_value(true).number_of_values(2).value_names(&["old", "new"]).conflicts_with_all(&["name", "rev"]))
+        )
+        .subcommand(
+            App::new("tag")
+                .about("List, create or delete tags")
+                .arg(Arg::new("name").about("Tag to create").required(false))
+                .arg(Arg::new("rev").about("Revision to tag (default: current revision)").required(false))
+                .arg(Arg::new("message").about("Tag message").short('m').long("message").takes_value(true).requires("name"))
+                .arg(Arg::new("delete").about("Delete a tag").short('d').long("delete").takes_value(true).conflicts_with_all(&["name", "rev", "message"]))
+        )
         .subcommand(
             App::new("diff")
                 .about("Check the changes between revisions")
+                .arg(Arg::new("rev1").about("Revision to compare from (default: the staged files, or the current revision with --staged)").required(false))
+                .arg(Arg::new("rev2").about("Revision to compare to (default: working tree)").required(false))
+                .arg(Arg::new("staged").about("Compare the staged files instead of the working tree").long("staged").alias("cached").conflicts_with("rev2"))
+                .arg(Arg::new("stat").about("Show a summary of changed lines per file").long("stat"))
+                .arg(Arg::new("name-only").about("Show only the names of changed files").long("name-only").conflicts_with("stat"))
+                .arg(Arg::new("paths").about("Only compare these paths").multiple(true).last(true))
         )
         .subcommand(
             App::new("cat")
                 .about("Inspect a file of a given revision")
+                .arg(Arg::new("rev").about("Revision to read the file from").required(true))
                 .arg(Arg::new("path").about("File path of inspected file").required(true))
         )
+        .subcommand(
+            App::new("reset")
+                .about("Unstage files, leaving the working tree alone")
+                .arg(Arg::new("path").about("Files or directories to unstage (default: everything)").multiple(true).required(false))
+        )
+        .subcommand(
+            App::new("restore")
+                .about("Bring back files from a revision, throwing away their changes")
+                .alias("revert-file")
+                .arg(Arg::new("source").about("Revision to take the files from (default: current revision)").short('s').long("source").takes_value(true))
+                .arg(Arg::new("path").about("Files, directories or globs to restore").multiple(true).required(true))
+        )
         .subcommand(
             App::new("checkout")
-                .about("Check out a specific revision")
-                .arg(Arg::new("rev").about("Revision to checkout to").required(true))
+                .about("Check out a specific revision or branch")
+                .arg(Arg::new("rev").about("Revision or branch to checkout to").required(true))
+                .arg(Arg::new("force").about("Throw away uncommitted changes to tracked files").short('f').long("force"))
+                .arg(Arg::new("carry").about("Take uncommitted changes along, if they merge cleanly").long("carry").conflicts_with("force"))
         )
         .subcommand(
             App::new("commit")
                 .about("Commit changes")
+                .arg(Arg::new("message").about("Commit message").short('m').long("message").takes_value(true))
+                .arg(Arg::new("file").about("Read the commit message from a file").short('F').long("file").takes_value(true).conflicts_with("message"))
+                .arg(Arg::new("all").about("Stage the changes to every tracked file first").short('a').long("all"))
+                .arg(Arg::new("interactive").about("Pick the hunks to commit one by one").short('i').long("interactive").conflicts_with("all"))
+                .arg(Arg::new("patch").about("Commit only the hunks in a patch file, such as arc diff HEAD prints").long("patch").takes_value(true).conflicts_with_all(&["all", "interactive"]))
+        )
+        .subcommand(
+            App::new("log")
+                .about("Show the revision history")
+                .arg(Arg::new("rev").about("Revision to start from").required(false))
+                .arg(Arg::new("limit").about("Show at most this many revisions").short('n').takes_value(true))
+                .arg(Arg::new("oneline").about("Show one revision per line").long("oneline"))
+                .arg(Arg::new("paths").about("Only show revisions touching these paths").multiple(true).last(true))
         )
         .subcommand(
             App::new("merge")
                 .about("Merge two revisions")
-                .arg(Arg::new("rev1").about("First revision to merge").required(true))
-                .arg(Arg::new("rev2").about("Second revision to merge").required(true))
+                .arg(Arg::new("rev1").about("First revision to merge").required_unless_present_any(["continue", "abort"]))
+                .arg(Arg::new("rev2").about("Second revision to merge").required(false))
+                .arg(Arg::new("continue").about("Commit a merge once its conflicts are resolved").long("continue").conflicts_with_all(&["rev1", "abort"]))
+                .arg(Arg::new("abort").about("Give up on a conflicted merge").long("abort").conflicts_with("rev1"))
+                .arg(Arg::new("force").about("Throw away uncommitted changes to tracked files").short('f').long("force"))
+                .arg(Arg::new("carry").about("Take uncommitted changes along, if they merge cleanly").long("carry").conflicts_with("force"))
+        )
+        .subcommand(
+            App::new("resolve")
+                .about("List merge conflicts or mark them as resolved")
+                .arg(Arg::new("mark").about("Mark files as resolved").long("mark").takes_value(true).multiple(true))
+        )
+        .subcommand(
+            App::new("stash")
+                .about("Shelve changes to tracked files and bring them back later")
+                .subcommand(
+                    App::new("push")
+                        .about("Save the changes and reset the working tree (the default)")
+                        .arg(Arg::new("message").about("Describe the stash").short('m').long("message").takes_value(true))
+                )
+                .subcommand(
+                    App::new("pop")
+                        .about("Apply a stash to the current revision and drop it")
+                        .arg(Arg::new("stash").about("Stash to apply, as N or stash@{N} (default: 0)").required(false))
+                )
+                .subcommand(
+                    App::new("list")
+                        .about("List the stashes, most recent first")
+                )
+                .subcommand(
+                    App::new("drop")
+                        .about("Delete a stash")
+                        .arg(Arg::new("stash").about("Stash to delete (default: 0)").required(false))
+                )
+                .subcommand(
+                    App::new("show")
+                        .about("Show the changes in a stash")
+                        .arg(Arg::new("stash").about("Stash to show (default: 0)").required(false))
+                        .arg(Arg::new("patch").about("Show the changes as a patch").short('p').long("patch"))
+                )
         )
         .subcommand(
             App::new("push")
@@ -73,111 +171,139 @@ fn main() {
         .subcommand(
             App::new("pull")
                 .about("Pull changes")
+                .arg(Arg::new("force").about("Throw away uncommitted changes to tracked files").short('f').long("force"))
+                .arg(Arg::new("carry").about("Take uncommitted changes along, if they merge cleanly").long("carry").conflicts_with("force"))
         )
         .get_matches();
 
-        match matches.subcommand() {
-            Some(("init", init_matches)) => {
-                let mut args = Vec::new();
-                let mut directory = String::new();
-                if init_matches.is_present("directory") {
-                    directory = init_matches.value_of("directory").unwrap().to_string();
+        let cwd = mach::get_cwd().unwrap_or_else(|e| fail(e));
+        let command = match matches.subcommand() {
+            Some(("init", m)) => Command::Init { directory: m.value_of("directory").map_or(cwd, |d| d.to_string()) },
+            Some(("print", m)) => Command::Print { path: m.value_of("path").map_or(cwd, |p| p.to_string()) },
+            Some(("status", m)) => Command::Status { porcelain: m.is_present("porcelain") },
+            Some(("clone", m)) => Command::Clone {
+                src: m.value_of("src").unwrap().to_string(),
+                dst: m.value_of("dst").map_or(cwd, |d| d.to_string()),
+            },
+            Some(("add", m)) => Command::Add { paths: values(m, "path"), all: m.is_present("all"), force: m.is_present("force") },
+            Some(("remove", m)) => Command::Remove { paths: values(m, "path"), cached: m.is_present("cached"), force: m.is_present("force") },
+            Some(("heads", _)) => Command::Heads,
+            Some(("branch", m)) => {
+                let moved = values(m, "move");
+                if let Some(name) = m.value_of("delete") {
+                    Command::DeleteBranch { name: name.to_string() }
+                } else if moved.len() == 2 {
+                    Command::RenameBranch { old_name: moved[0].clone(), new_name: moved[1].clone() }
+                } else if let Some(name) = m.value_of("name") {
+                    Command::CreateBranch { name: name.to_string(), rev: value(m, "rev") }
                 } else {
-                    directory = mach::get_cwd();
+                    Command::ListBranches
                 }
-                args.push(&directory);
-                cmd::command("init".to_string(), args)
             }
-            Some(("print", print_matches)) => {
-                let mut args = Vec::new();
-                let mut path = String::new();
-                if print_matches.is_present("path") {
-                    path = print_matches.value_of("path").unwrap().to_string();
+            Some(("tag", m)) => {
+                if let Some(name) = m.value_of("delete") {
+                    Command::DeleteTag { name: name.to_string() }
+                } else if let Some(name) = m.value_of("name") {
+                    Command::CreateTag { name: name.to_string(), rev: value(m, "rev"), message: value(m, "message") }
                 } else {
-                    path = mach::get_cwd();
+                    Command::ListTags
                 }
-                args.push(&path);
-                cmd::command("print".to_string(), args)
             }
-            Some(("clone", clone_matches)) => {
-                let mut args = Vec::new();
-                let mut src = String::new();
-                let mut dst = String::new();
-                if clone_matches.is_present("src") {
-                    src = clone_matches.value_of("src").unwrap().to_string();
-                }
-                args.push(&src);
-                if clone_matches.is_present("dst") {
-                    dst = clone_matches.value_of("dst").unwrap().to_string();
+            Some(("diff", m)) => {
+                let format = if m.is_present("stat") {
+                    DiffFormat::Stat
+                } else if m.is_present("name-only") {
+                    DiffFormat::NameOnly
                 } else {
-                    dst = mach::get_cwd();
-                }
-                args.push(&dst);
-                cmd::command("clone".to_string(), args)
+                    DiffFormat::Patch
+                };
+                let revs = value(m, "rev1").into_iter().chain(value(m, "rev2")).collect();
+                Command::Diff { revs, staged: m.is_present("staged"), format, paths: values(m, "paths") }
             }
-            Some(("add", add_matches)) => {
-                let mut args = Vec::new();
-                let mut path = String::new();
-                if add_matches.is_present("path") {
-                    path = add_matches.value_of("path").unwrap().to_string();
-                }
-                args.push(&path);
-                cmd::command("add".to_string(), args);
-            }
-            Some(("remove", remove_matches)) => {
-                let mut args = Vec::new();
-                let mut path = String::new();
-                if remove_matches.is_present("path") {
-                    path = remove_matches.value_of("path").unwrap().to_string();
-                }
-                args.push(&path);
-                cmd::command("remove".to_string(), args);
-            }
-            Some(("heads", heads_matches)) => {
-                println!("arc heads was used");
-            }
-            Some(("diff", diff_matches)) => {
-                println!("arc diff was used");
+            Some(("cat", m)) => Command::Cat { rev: m.value_of("rev").unwrap().to_string(), path: m.value_of("path").unwrap().to_string() },
+            Some(("reset", m)) => Command::Reset { paths: values(m, "path") },
+            Some(("restore", m)) => Command::Restore { source: value(m, "source"), paths: values(m, "path") },
+            Some(("checkout", m)) => Command::Checkout { rev: m.value_of("rev").unwrap().to_string(), local: local_changes(m) },
+            Some(("commit", m)) => {
+                let staging = if m.is_present("all") {
+                    Staging::All
+                } else if m.is_present("interactive") {
+                    Staging::Interactive
+                } else if let Some(p) = m.value_of("patch") {
+                    Staging::Patch(p.to_string())
+                } else {
+                    Staging::Index
+                };
+                Command::Commit { staging, message: value(m, "message"), message_file: value(m, "file") }
             }
-            Some(("cat", cat_matches)) => {
-                println!("arc cat was used");
+            Some(("log", m)) => {
+                let limit = m.value_of("limit").map(|n| {
+                    n.parse::<usize>().unwrap_or_else(|_| fail(ArcError::BadArgument(format!("bad revision limit: {}", n))))
+                });
+                Command::Log { rev: value(m, "rev"), limit, oneline: m.is_present("oneline"), paths: values(m, "paths") }
             }
-            Some(("checkout", checkout_matches)) => {
-                let mut args = Vec::new();
-                let mut rev = String::new();
-                if checkout_matches.is_present("rev") {
-                    rev = checkout_matches.value_of("rev").unwrap().to_string();
+            Some(("merge", m)) => {
+                if m.is_present("continue") {
+                    Command::MergeContinue
+                } else if m.is_present("abort") {
+                    Command::MergeAbort
+                } else {
+                    let rev1 = m.value_of("rev1").unwrap().to_string();
+                    match value(m, "rev2") {
+                        Some(rev2) => Command::Merge { trunk: Some(rev1), other: rev2, local: local_changes(m) },
+                        None => Command::Merge { trunk: None, other: rev1, local: local_changes(m) },
+                    }
                 }
-                args.push(&rev);
-                cmd::command("checkout".to_string(), args);
             }
-            Some(("commit", commit_matches)) => {
-                let args = Vec::new();
-                cmd::command("commit".to_string(), args)
-            }
-            Some(("merge", merge_matches)) => {
-                let mut args = Vec::new();
-                let mut rev1 = String::new();
-                let mut rev2 = String::new();
-                if merge_matches.is_present("rev1") {
-                    rev1 = merge_matches.value_of("rev1").unwrap().to_string();
-                }
-                if merge_matches.is_present("rev2") {
-                    rev2 = merge_matches.value_of("rev2").unwrap().to_string();
+            Some(("resolve", m)) => Command::Resolve { mark: values(m, "mark") },
+            Some(("stash", stash_matches)) => {
+                let index = |m: &ArgMatches| {
+                    m.value_of("stash").map_or(0, |s| stash::parse_index(s).unwrap_or_else(|e| fail(e)))
+                };
+                match stash_matches.subcommand() {
+                    Some(("pop", m)) => Command::StashPop { index: index(m) },
+                    Some(("list", _)) => Command::StashList,
+                    Some(("drop", m)) => Command::StashDrop { index: index(m) },
+                    Some(("show", m)) => Command::StashShow { index: index(m), patch: m.is_present("patch") },
+                    Some((_, m)) => Command::StashPush { message: value(m, "message") },
+                    None => Command::StashPush { message: None },
                 }
-                args.push(&rev1);
-                args.push(&rev2);
-                cmd::command("merge".to_string(), args);
             }
-            Some(("push", push_matches)) => {
-                let args = Vec::new();
-                cmd::command("push".to_string(), args)
+            Some(("push", _)) => Command::Push,
+            Some(("pull", m)) => Command::Pull { local: local_changes(m) },
+            None => {
+                println!("No subcommand was used");
+                return;
             }
-            Some(("pull", pull_matches)) => {
-                let args = Vec::new();
-                cmd::command("pull".to_string(), args)
-            }
-            None => println!("No subcommand was used"),
             _ => unreachable!(), 
+        };
+        
+        if let Err(e) = cmd::run(command) {
+            fail(e);
         }
 }
+
+fn value(m: &ArgMatches, name: &str) -> Option<String> {
+    m.value_of(name).map(|v| v.to_string())
+}
+
+fn values(m: &ArgMatches, name: &str) -> Vec<String> {
+    m.values_of(name).map_or(Vec::new(), |v| v.map(|p| p.to_string()).collect())
+}
+
+// --force discards uncommitted changes and --carry takes them along
+fn local_changes(m: &ArgMatches) -> LocalChanges {
+    if m.is_present("force") {
+        LocalChanges::Discard
+    } else if m.is_present("carry") {
+        LocalChanges::Carry
+    } else {
+        LocalChanges::Refuse
+    }
+}
+
+// One line on stderr and an exit code that tells the kind of error apart
+fn fail(e: ArcError) -> ! {
+    eprintln!("arc: {}", e);
+    process::exit(e.exit_code());
+}
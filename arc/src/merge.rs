@@ -1,126 +1,182 @@
-use diffy;
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+use crate::mach;
 use crate::repo;
 use crate::rev;
 use crate::revid;
 use crate::revid::RevID;
+use crate::error::{ArcError, Result};
 
-pub fn can_reach_root(repo: &repo::Repo, from_id: &RevID, skip_id: &RevID) -> bool {
-    if from_id.is_empty() {
-        true
-    } else if from_id == skip_id {
-        false
-    } else {
-        let rev = rev::open(repo, from_id);
-        let parent_trunk_id = rev.get_parent_trunk_id();
-        let parent_other_id = rev.get_parent_other_id();
-        can_reach_root(repo, parent_trunk_id, skip_id) ||
-            (!parent_other_id.is_empty() && can_reach_root(repo, &parent_other_id, skip_id))
-    }
+/// Result of merging one file with [`merge3`].
+pub enum Merged {
+    /// The merged content; None when the file ends up deleted
+    Clean(Option<String>),
+    /// The file content with conflict markers
+    Conflict(String),
+}
+
+/// What [`Repo::merge`](crate::repo::Repo::merge) did.
+pub enum MergeOutcome {
+    /// The trunk already contains the other revision
+    UpToDate(rev::Rev),
+    /// The trunk is an ancestor of the other revision, which is the result
+    FastForward(rev::Rev),
+    /// A new merge revision was saved. `bases` are the lowest common
+    /// ancestors; when there are several they were merged first.
+    Merged { rev: rev::Rev, bases: Vec<RevID> },
+    /// The merge stopped; the conflicted files are left in the working tree
+    /// and the merge is tracked in MERGE_STATE
+    Conflicts { files: Vec<String>, bases: Vec<RevID> },
 }
 
-pub fn find_trunk_parents(repo: &repo::Repo, from_id: &RevID) -> Vec<RevID> {
-    let mut parents = Vec::new();
-    let mut id = *from_id;
-    while !id.is_empty() {
-        if !can_reach_root(repo, from_id, &id) {
-            parents.push(id.clone());
+impl MergeOutcome {
+    /// The revision to move to, or None if the merge stopped on conflicts
+    pub fn rev(&self) -> Option<&rev::Rev> {
+        match self {
+            MergeOutcome::UpToDate(r) | MergeOutcome::FastForward(r) | MergeOutcome::Merged { rev: r, .. } => Some(r),
+            MergeOutcome::Conflicts { .. } => None,
         }
-        
-        let r = rev::open(repo, &id);
-        id = *r.get_parent_trunk_id();
-        //println!("id: {}", id);
     }
-    parents
 }
 
-pub fn find_common_ancestor(repo: &repo::Repo, trunk_id: &RevID, other_id: &RevID) -> RevID {
-    let parents_trunk = find_trunk_parents(repo, trunk_id);
-    let parents_other = find_trunk_parents(repo, other_id);
-    
-    //println!("parents_trunk: {:?}", parents_trunk);
-    //println!("parents_other: {:?}", parents_other);
+/*
+ * State of a merge that stopped on conflicts, kept in .arc/MERGE_STATE
+ */
+/// A merge waiting for its conflicts to be resolved.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MergeState {
+    /// The revision checked out before the merge, for --abort
+    pub orig_rev: RevID,
+    /// The revision merged into
+    pub trunk: RevID,
+    /// The revision merged in
+    pub other: RevID,
+    /// Every file that had conflicts
+    pub conflicts: Vec<String>,
+    /// The conflicted files marked as resolved so far
+    pub resolved: Vec<String>,
+}
+
+impl MergeState {
+    /// Conflicted files not yet marked as resolved
+    pub fn unresolved(&self) -> Vec<String> {
+        self.conflicts.iter().filter(|f| !self.resolved.contains(f)).cloned().collect()
+    }
     
-    let mut anc_id = revid::EMPTY;
-    for p in &parents_other {
-        if parents_trunk.contains(p) && p != trunk_id && p != other_id {
-            anc_id = *p;
-            break;
-        }
+    /// Writes MERGE_STATE into the given .arc dir
+    pub fn save(&self, arc_path: &String) -> Result<()> {
+        let serialized = serde_json::to_string(self).unwrap();
+        mach::write_string(arc_path, &String::from("MERGE_STATE"), &serialized)
     }
-    anc_id
 }
 
-pub fn can_reach_rev(repo: &repo::Repo, cur_id: &RevID, target_id: &RevID) -> bool {
-    if cur_id.is_empty() {
-        false
-    } else if cur_id == target_id {
-        true
-    } else {
-        let rev = rev::open(repo, cur_id);
-        let parent_trunk_id = rev.get_parent_trunk_id();
-        let parent_other_id = rev.get_parent_other_id();
-        can_reach_rev(repo, parent_trunk_id, target_id) ||
-            (!parent_other_id.is_empty() && can_reach_rev(repo, parent_other_id, target_id))
+/// The merge in progress, if any
+pub fn load_state(arc_path: &String) -> Result<Option<MergeState>> {
+    if !mach::check_path(&mach::join_paths(arc_path, &String::from("MERGE_STATE"))) {
+        return Ok(None);
     }
+    let json = mach::read_line(arc_path, &String::from("MERGE_STATE"))?;
+    Ok(Some(serde_json::from_str(&json).map_err(ArcError::corrupt("MERGE_STATE"))?))
 }
 
-fn merge3_with_diffy(anc_s: &String, s1: &String, s2: &String) -> Option<String> {
-    let m = diffy::merge(anc_s, s1, s2);
-    let m = match m {
-        Ok(mok) => {
-            Some(mok.clone())
-        },
-        Err(mconf) => {
-            println!("Conflict detected!\n{}", &mconf);
-            None
-        }
-    };
-    m
+/// Forgets the merge in progress
+pub fn clear_state(arc_path: &String) -> Result<()> {
+    mach::del_file(arc_path, &String::from("MERGE_STATE"))
 }
 
-pub fn merge3(ancestor: Option<String>, trunk: Option<String>, other: Option<String>) -> Option<String> {
-    if ancestor.is_some() {
-        if trunk.is_some() {
-            if other.is_some() {
-                let m = merge3_with_diffy(&ancestor.unwrap(), &trunk.unwrap(), &other.unwrap());
-                assert!(m.is_some(), "Merge failed");
-                Some(m.unwrap().clone())
-            } else {
-                let m = merge3_with_diffy(&ancestor.unwrap(), &trunk.unwrap(), &"".to_string());
-                assert!(m.is_some(), "Merge failed");
-                let s = &m.unwrap();
-                if s.is_empty() { None } else { Some(s.clone()) }
-            }
-        } else if other.is_some() {
-            let m = merge3_with_diffy(&ancestor.unwrap(), &"".to_string(), &other.unwrap());
-            assert!(m.is_some(), "Merge failed");
-            let s = &m.unwrap();
-            if s.is_empty() { None } else { Some(s.clone()) }
-        } else {
-            None
-        }
-    }
+/*
+ * Common ancestors
+ */
+fn find_best_common_ancestors(repo: &repo::Repo, trunk_ids: &[RevID], other_ids: &[RevID]) -> Vec<RevID> {
+    let graph = repo.get_graph();
+    let trunk_ancestors = graph.find_ancestors(trunk_ids);
+    let other_ancestors = graph.find_ancestors(other_ids);
+    let common: Vec<RevID> = trunk_ancestors.intersection(&other_ancestors).cloned().collect();
     
-    else if trunk.is_some() {
-        if other.is_some() {
-            let m = merge3_with_diffy(&"".to_string(), &trunk.unwrap(), &other.unwrap());
-            assert!(m.is_some(), "Merge failed");
-            Some(m.unwrap().clone())
-        } else {
-            Some(trunk.unwrap().clone())
+    // Drop every common ancestor that is itself an ancestor of another one
+    let mut dominated = HashSet::new();
+    for id in &common {
+        if !dominated.contains(id) {
+            dominated.extend(graph.find_ancestors(&graph.get_parents(id)));
         }
     }
     
-    else if other.is_some() {
-        Some(other.unwrap().clone())
+    let mut best: Vec<RevID> = common.into_iter().filter(|id| !dominated.contains(id)).collect();
+    best.sort_by_key(|id| id.to_string());
+    best
+}
+
+// With several lowest common ancestors, they are merged together (recursively)
+// into a virtual revision that is used as the merge base. The virtual revision
+// is never saved; conflicts inside it are kept with their markers.
+fn find_virtual_base(repo: &repo::Repo, trunk_ids: &[RevID], other_ids: &[RevID]) -> Result<Option<(rev::Rev, Vec<RevID>)>> {
+    let ancestors = find_best_common_ancestors(repo, trunk_ids, other_ids);
+    if ancestors.is_empty() {
+        return Ok(None);
     }
     
-    else {
-        None
+    let mut base = rev::open(repo, &ancestors[0])?;
+    let mut base_ids = vec![ancestors[0]];
+    for id in &ancestors[1..] {
+        let next = rev::open(repo, id)?;
+        let inner_base = match find_virtual_base(repo, &base_ids, &[*id])? {
+            Some((r, _)) => r,
+            None => rev::new(repo, &revid::EMPTY, &revid::EMPTY),
+        };
+        
+        let mut merged = rev::new(repo, &revid::EMPTY, &revid::EMPTY);
+        for (f, content) in merged.merge(&inner_base, &base, &next)? {
+            merged.set_file(&f, content.as_bytes())?;
+        }
+        base = merged;
+        base_ids.push(*id);
+    }
+    Ok(Some((base, base_ids)))
+}
+
+/// The revision to merge against: the lowest common ancestor, or a virtual
+/// revision merged from all of them when there are several (criss-cross
+/// histories), along with the lowest common ancestors. None if the revisions
+/// share no history.
+pub fn find_merge_base(repo: &repo::Repo, trunk_id: &RevID, other_id: &RevID) -> Result<Option<(rev::Rev, Vec<RevID>)>> {
+    find_virtual_base(repo, &[*trunk_id], &[*other_id])
+}
+
+/// Whether `target_id` is `cur_id` or one of its ancestors.
+pub fn can_reach_rev(repo: &repo::Repo, cur_id: &RevID, target_id: &RevID) -> bool {
+    !cur_id.is_empty() && repo.get_graph().can_reach(cur_id, target_id)
+}
+
+fn merge3_with_diffy(anc_s: &str, s1: &str, s2: &str) -> Merged {
+    match diffy::merge(anc_s, s1, s2) {
+        Ok(m) => Merged::Clean(Some(m)),
+        Err(conflict) => Merged::Conflict(conflict),
+    }
+}
+
+// A deleted file merges like an empty one, and stays deleted if the merge is empty
+fn drop_if_empty(m: Merged) -> Merged {
+    match m {
+        Merged::Clean(Some(s)) if s.is_empty() => Merged::Clean(None),
+        m => m,
+    }
+}
+
+/// Three-way merge of one file; None stands for a missing file.
+pub fn merge3(ancestor: Option<String>, trunk: Option<String>, other: Option<String>) -> Merged {
+    match (ancestor, trunk, other) {
+        (Some(a), Some(t), Some(o)) => merge3_with_diffy(&a, &t, &o),
+        (Some(a), Some(t), None) => drop_if_empty(merge3_with_diffy(&a, &t, "")),
+        (Some(a), None, Some(o)) => drop_if_empty(merge3_with_diffy(&a, "", &o)),
+        (None, Some(t), Some(o)) => merge3_with_diffy("", &t, &o),
+        (None, Some(t), None) => Merged::Clean(Some(t)),
+        (None, None, Some(o)) => Merged::Clean(Some(o)),
+        (_, None, None) => Merged::Clean(None),
     }
 }
 
-pub fn find_all_files(v1: &Vec<String>, v2: &Vec<String>, v3: &Vec<String>) -> Vec<String> {
+/// Every file in any of the three lists, each once, in the order first seen
+pub fn find_all_files(v1: &[String], v2: &[String], v3: &[String]) -> Vec<String> {
     let mut files = Vec::new();
     
     for f in v1 {
@@ -0,0 +1,105 @@
+use serde::{Serialize, Deserialize};
+use crate::mach;
+use crate::revid;
+use crate::revid::RevID;
+use crate::error::{ArcError, Result};
+
+/*
+ * Named references under .arc/refs
+ *
+ * A branch is a file in refs/heads named after the branch and holding the
+ * ID of the revision it points at. A tag is a file in refs/tags holding a
+ * Tag; unlike branches, tags never move once created.
+ */
+
+pub fn heads_path(arc_path: &String) -> String {
+    mach::join_paths(arc_path, &"refs/heads".to_string())
+}
+
+// Names end up as file names, so keep them to a safe set of characters
+pub fn is_valid_name(name: &String) -> bool {
+    !name.is_empty()
+        && name != "HEAD"
+        && !name.starts_with('-')
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+pub fn list_branches(arc_path: &String) -> Result<Vec<String>> {
+    let p = heads_path(arc_path);
+    if !mach::check_path(&p) {
+        return Ok(Vec::new());
+    }
+    mach::list_files(&p)
+}
+
+pub fn contains_branch(arc_path: &String, name: &String) -> bool {
+    is_valid_name(name) && mach::check_path(&mach::join_paths(&heads_path(arc_path), name))
+}
+
+pub fn read_branch(arc_path: &String, name: &String) -> Result<Option<RevID>> {
+    if !contains_branch(arc_path, name) {
+        return Ok(None);
+    }
+    let id = mach::read_line(&heads_path(arc_path), name)?;
+    match revid::parse(id.trim()) {
+        Ok(id) => Ok(Some(id)),
+        Err(_) => Err(ArcError::Corrupt(format!("bad branch file {}", name))),
+    }
+}
+
+pub fn write_branch(arc_path: &String, name: &String, rev_id: &RevID) -> Result<()> {
+    mach::write_bytes(&heads_path(arc_path), name, rev_id.to_string().as_bytes())
+}
+
+pub fn delete_branch(arc_path: &String, name: &String) -> Result<()> {
+    mach::del_file(&heads_path(arc_path), name)
+}
+
+/*
+ * Tags
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub rev_id: RevID,
+    // Only annotated tags (created with a message) record these
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+pub fn tags_path(arc_path: &String) -> String {
+    mach::join_paths(arc_path, &"refs/tags".to_string())
+}
+
+pub fn list_tags(arc_path: &String) -> Result<Vec<String>> {
+    let p = tags_path(arc_path);
+    if !mach::check_path(&p) {
+        return Ok(Vec::new());
+    }
+    mach::list_files(&p)
+}
+
+pub fn contains_tag(arc_path: &String, name: &String) -> bool {
+    is_valid_name(name) && mach::check_path(&mach::join_paths(&tags_path(arc_path), name))
+}
+
+pub fn read_tag(arc_path: &String, name: &String) -> Result<Option<Tag>> {
+    if !contains_tag(arc_path, name) {
+        return Ok(None);
+    }
+    let json = mach::read_line(&tags_path(arc_path), name)?;
+    Ok(Some(serde_json::from_str(&json).map_err(ArcError::corrupt("tag file"))?))
+}
+
+pub fn write_tag(arc_path: &String, name: &String, tag: &Tag) -> Result<()> {
+    let serialized = serde_json::to_string(tag).unwrap();
+    mach::write_bytes(&tags_path(arc_path), name, serialized.as_bytes())
+}
+
+pub fn delete_tag(arc_path: &String, name: &String) -> Result<()> {
+    mach::del_file(&tags_path(arc_path), name)
+}
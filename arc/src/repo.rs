@@ -1,10 +1,21 @@
 use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use crate::mach;
 use crate::rev;
 use crate::revid;
 use crate::revid::RevID;
 use crate::merge;
+use crate::diff;
+use crate::store;
+use crate::graph;
+use crate::refs;
+use crate::revspec;
+use crate::ignore;
+use crate::stash;
+use crate::index;
+use crate::status;
+use crate::error::{ArcError, Result};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct RepoInfo {
@@ -13,231 +24,1105 @@ struct RepoInfo {
     tracked_files: Vec<String>,
     all_revs: Vec<RevID>,
     cur_rev: RevID,
+    #[serde(default)]
+    active_branch: Option<String>,
 }
 
+/// A repository: its working tree at `root_path` and its history in `arc_path`
+/// (the .arc dir). Changes to the repo itself are written by [`Repo::save`];
+/// the operations below save when they are done.
 pub struct Repo {
+    /// Root of the working tree
     pub root_path: String,
+    /// The .arc dir
     pub arc_path: String,
     repo: RepoInfo,
+    graph: graph::CommitGraph,
+    // Staged blob of each tracked file
+    index: BTreeMap<String, String>,
 }
 
-impl fmt::Display for RepoInfo {
+impl fmt::Display for Repo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Upstream @ {}\n", self.upstream)?;
-        write!(f, "Root @ {}\n", self.root_path)?;
-        write!(f, "Current Revision: {}\n", self.cur_rev)?;
-        write!(f, "All Revisions:\n")?;
-        for l in &self.all_revs {
-            write!(f, "  {}\n", l.to_string())?;
+        writeln!(f, "Root Path @ {}", self.root_path)?;
+        writeln!(f, ".arc Path @ {}", self.arc_path)?;
+        writeln!(f, "Upstream @ {}", self.repo.upstream)?;
+        writeln!(f, "Root @ {}", self.repo.root_path)?;
+        writeln!(f, "Current Revision: {}", self.repo.cur_rev)?;
+        if let Some(b) = &self.repo.active_branch {
+            writeln!(f, "Current Branch: {}", b)?;
+        }
+        writeln!(f, "All Revisions:")?;
+        for l in &self.repo.all_revs {
+            match rev::open(self, l) {
+                Ok(r) => writeln!(f, "  {} {} {} {}", l, mach::format_timestamp(r.get_timestamp()), r.get_author(), r.get_summary())?,
+                Err(e) => writeln!(f, "  {} ({})", l, e)?,
+            }
         }
-        write!(f, "Tracked files:\n")?;
-        for l in &self.tracked_files {
-            write!(f, "  {}\n", l)?;
+        writeln!(f, "Tracked files:")?;
+        for l in &self.repo.tracked_files {
+            writeln!(f, "  {}", l)?;
         }
+        writeln!(f)?;
         Ok(())
     }
 }
 
-impl fmt::Display for Repo {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Root Path @ {}\n", self.root_path)?;
-        write!(f, ".arc Path @ {}\n", self.arc_path)?;
-        write!(f, "{}\n", self.repo)?;
-        Ok(())
-    }
+/// What to do with uncommitted changes to tracked files when the working
+/// tree is about to be replaced by another revision's files
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LocalChanges {
+    /// Stop with [`ArcError::DirtyFiles`]
+    Refuse,
+    /// Throw them away
+    Discard,
+    /// Merge them into the other revision's files, stopping with
+    /// [`ArcError::CarryConflict`] if any of them don't merge cleanly
+    Carry,
+}
+
+/// What [`Repo::sync`] copied over, and the tags it had to leave alone
+pub struct SyncOutcome {
+    /// Revisions copied over, parents first
+    pub revs: Vec<RevID>,
+    /// Tags copied over
+    pub tags: Vec<String>,
+    /// Tags whose name is a local branch
+    pub skipped_tags: Vec<String>,
+    /// Tags both repos have, as (name, local revision, other revision)
+    pub kept_tags: Vec<(String, RevID, RevID)>,
 }
 
 impl Repo {
-    pub fn set_upstream(&mut self, ups: &String) {
+    /// Sets the repo that push and pull go to
+    pub fn set_upstream(&mut self, ups: &str) {
         self.repo.upstream = ups.to_string();
     }
     
+    /// Path of the repo that push and pull go to
     pub fn get_upstream(&self) -> &String {
         &self.repo.upstream
     }
     
-    pub fn save(&self) {
+    /// Writes repo.json, the index and the commit-graph
+    pub fn save(&self) -> Result<()> {
         let serialized = serde_json::to_string(&self.repo).unwrap();
-        mach::write_string(&self.arc_path, &String::from("repo.json"), &serialized);
+        mach::write_string(&self.arc_path, &String::from("repo.json"), &serialized)?;
+        index::save(&self.arc_path, &self.index)?;
+        self.graph.save(&self.arc_path)
+    }
+    
+    /// The parents and generation of every revision
+    pub fn get_graph(&self) -> &graph::CommitGraph {
+        &self.graph
+    }
+    
+    // Rebuilds the commit-graph index from the rev.json of every revision
+    fn rebuild_graph(&mut self) -> Result<()> {
+        let mut g = graph::new();
+        let mut stack: Vec<(RevID, bool)> = self.repo.all_revs.iter().rev().map(|id| (*id, false)).collect();
+        while let Some((id, parents_added)) = stack.pop() {
+            if id.is_empty() || g.contains(&id) {
+                continue;
+            }
+            let r = rev::open(self, &id)?;
+            if parents_added {
+                g.add(&id, r.get_parent_trunk_id(), r.get_parent_other_id())?;
+            } else {
+                stack.push((id, true));
+                stack.push((*r.get_parent_other_id(), false));
+                stack.push((*r.get_parent_trunk_id(), false));
+            }
+        }
+        self.graph = g;
+        Ok(())
+    }
+    
+    // Repos from before the blob store keep a copy of the files in each
+    // revision dir and have random revision IDs. Their revisions are moved
+    // into the store, parents first, getting IDs derived from their content.
+    // Returns the old revision dirs, which can go once the repo is saved.
+    fn upgrade_revs(&mut self) -> Result<Vec<RevID>> {
+        match self.repo.all_revs.first() {
+            Some(id) if rev::old_parents(self, id)?.is_some() => {},
+            _ => return Ok(Vec::new()),
+        }
+        mach::create_dir_all(&store::objects_path(&self.arc_path))?;
+        
+        let mut new_ids: HashMap<RevID, RevID> = HashMap::new();
+        new_ids.insert(revid::EMPTY, revid::EMPTY);
+        let mut stack: Vec<(RevID, bool)> = self.repo.all_revs.iter().rev().map(|id| (*id, false)).collect();
+        while let Some((id, parents_added)) = stack.pop() {
+            if new_ids.contains_key(&id) {
+                continue;
+            }
+            let (trunk_id, other_id) = rev::old_parents(self, &id)?.ok_or_else(|| ArcError::Corrupt(format!("revision {} mixes old and new revision formats", id)))?;
+            if parents_added {
+                let r = rev::upgrade(self, &id, &new_ids[&trunk_id], &new_ids[&other_id])?;
+                new_ids.insert(id, *r.get_id());
+            } else {
+                stack.push((id, true));
+                stack.push((other_id, false));
+                stack.push((trunk_id, false));
+            }
+        }
+        
+        let old_ids = self.repo.all_revs.clone();
+        self.repo.all_revs = old_ids.iter().map(|id| new_ids[id]).collect();
+        self.repo.cur_rev = *new_ids.get(&self.repo.cur_rev).ok_or(ArcError::UnknownRevision(self.repo.cur_rev.to_string()))?;
+        Ok(old_ids)
     }
     
+    /// Records the checked out revision, leaving the working tree alone
     pub fn set_head_rev(&mut self, rev_id: &RevID) {
-        self.repo.cur_rev = rev_id.clone();
+        self.repo.cur_rev = *rev_id;
+    }
+    
+    /// The checked out revision, EMPTY in a repo without revisions
+    pub fn get_head_rev(&self) -> RevID {
+        self.repo.cur_rev
+    }
+    
+    /// Opens the checked out revision, None in a repo without revisions
+    pub fn head_rev(&self) -> Result<Option<rev::Rev>> {
+        if self.repo.cur_rev.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(rev::open(self, &self.repo.cur_rev)?))
     }
     
+    /// The checked out revision as a string, for the revision resolving calls
     pub fn get_head_rev_str(&self) -> String {
         self.repo.cur_rev.to_string()
     }
     
-    pub fn add_rev(&mut self, rev_id: &RevID) {
-        self.repo.all_revs.push(rev_id.clone());
+    /// Resolves a revision name; see [`revspec`] for the accepted forms
+    pub fn resolve(&self, spec: &String) -> Result<RevID> {
+        revspec::resolve(self, spec)
     }
     
+    /// Like resolve, but the revision also has to be part of this repo
+    pub fn resolve_known(&self, spec: &String) -> Result<RevID> {
+        let rev_id = self.resolve(spec)?;
+        if !self.contains_rev(&rev_id) {
+            return Err(ArcError::UnknownRevision(spec.clone()));
+        }
+        Ok(rev_id)
+    }
+    
+    /// Makes a revision saved in .arc part of the repo's history
+    pub fn add_rev(&mut self, rev_id: &RevID) -> Result<()> {
+        if !self.contains_rev(rev_id) {
+            let r = rev::open(self, rev_id)?;
+            self.graph.add(rev_id, r.get_parent_trunk_id(), r.get_parent_other_id())?;
+            self.repo.all_revs.push(*rev_id);
+        }
+        Ok(())
+    }
+    
+    /// Whether the revision is part of this repo's history
     pub fn contains_rev(&self, rev_id: &RevID) -> bool {
-        self.repo.all_revs.contains(&rev_id)
+        self.repo.all_revs.contains(rev_id)
+    }
+    
+    /// Every revision in the repo, parents before their children
+    pub fn get_all_revs(&self) -> &Vec<RevID> {
+        &self.repo.all_revs
+    }
+    
+    /// The revisions no other revision names as a parent
+    pub fn get_heads(&self) -> Vec<RevID> {
+        self.graph.find_heads()
+    }
+    
+    fn check_no_merge(&self) -> Result<()> {
+        if merge::load_state(&self.arc_path)?.is_some() {
+            return Err(ArcError::MergeInProgress);
+        }
+        Ok(())
+    }
+    
+    /*
+     * Branches
+     */
+    /// The branch that commits move, None when no branch is checked out
+    pub fn get_active_branch(&self) -> Option<&String> {
+        self.repo.active_branch.as_ref()
+    }
+    
+    /// Every branch with the revision it points at
+    pub fn get_branches(&self) -> Result<Vec<(String, RevID)>> {
+        let mut branches = Vec::new();
+        for name in refs::list_branches(&self.arc_path)? {
+            let id = refs::read_branch(&self.arc_path, &name)?.ok_or_else(|| ArcError::NoSuchBranch(name.clone()))?;
+            branches.push((name, id));
+        }
+        Ok(branches)
+    }
+    
+    // Branches and tags share one namespace, and neither may look like a revision ID
+    fn check_new_ref_name(&self, name: &String) -> Result<()> {
+        if !refs::is_valid_name(name) || revid::parse(name).is_ok() {
+            return Err(ArcError::InvalidName(name.clone()));
+        }
+        if refs::contains_branch(&self.arc_path, name) || refs::contains_tag(&self.arc_path, name) {
+            return Err(ArcError::NameTaken(name.clone()));
+        }
+        Ok(())
+    }
+    
+    /// Creates a branch at a revision, returning the revision
+    pub fn create_branch(&mut self, name: &String, rev_id_str: &String) -> Result<RevID> {
+        self.check_new_ref_name(name)?;
+        let rev_id = self.resolve_known(rev_id_str)?;
+        
+        refs::write_branch(&self.arc_path, name, &rev_id)?;
+        Ok(rev_id)
+    }
+    
+    /// Deletes a branch; the active branch can't be deleted
+    pub fn delete_branch(&mut self, name: &String) -> Result<()> {
+        if !refs::contains_branch(&self.arc_path, name) {
+            return Err(ArcError::NoSuchBranch(name.clone()));
+        }
+        if self.get_active_branch() == Some(name) {
+            return Err(ArcError::ActiveBranch(name.clone()));
+        }
+        
+        refs::delete_branch(&self.arc_path, name)?;
+        Ok(())
+    }
+    
+    /// Renames a branch, keeping it active if it was
+    pub fn rename_branch(&mut self, old_name: &String, new_name: &String) -> Result<()> {
+        let rev_id = refs::read_branch(&self.arc_path, old_name)?.ok_or_else(|| ArcError::NoSuchBranch(old_name.clone()))?;
+        self.check_new_ref_name(new_name)?;
+        
+        refs::write_branch(&self.arc_path, new_name, &rev_id)?;
+        refs::delete_branch(&self.arc_path, old_name)?;
+        if self.get_active_branch() == Some(old_name) {
+            self.repo.active_branch = Some(new_name.clone());
+            self.save()?;
+        }
+        Ok(())
+    }
+    
+    // The active branch only follows the head forward, onto revisions that
+    // descend from where it was
+    fn advance_branch(&mut self, old_head: &RevID) -> Result<()> {
+        if let Some(name) = self.repo.active_branch.clone() {
+            let head = self.repo.cur_rev;
+            if old_head.is_empty() || head == *old_head || merge::can_reach_rev(self, &head, old_head) {
+                refs::write_branch(&self.arc_path, &name, &head)?;
+            } else {
+                self.repo.active_branch = None;
+            }
+        }
+        Ok(())
+    }
+    
+    /*
+     * Tags
+     */
+    /// Every tag by name
+    pub fn get_tags(&self) -> Result<Vec<(String, refs::Tag)>> {
+        let mut tags = Vec::new();
+        for name in refs::list_tags(&self.arc_path)? {
+            let tag = refs::read_tag(&self.arc_path, &name)?.ok_or_else(|| ArcError::NoSuchTag(name.clone()))?;
+            tags.push((name, tag));
+        }
+        Ok(tags)
+    }
+    
+    /// Tags a revision, returning it. A message makes an annotated tag,
+    /// which also records who made it and when.
+    pub fn create_tag(&mut self, name: &String, rev_id_str: &String, message: Option<&String>) -> Result<RevID> {
+        self.check_new_ref_name(name)?;
+        let rev_id = self.resolve_known(rev_id_str)?;
+        
+        let tag = refs::Tag {
+            rev_id,
+            message: message.cloned(),
+            author: message.map(|_| mach::get_author()),
+            timestamp: message.map(|_| mach::get_timestamp()),
+        };
+        refs::write_tag(&self.arc_path, name, &tag)?;
+        Ok(rev_id)
+    }
+    
+    /// Deletes a tag
+    pub fn delete_tag(&mut self, name: &String) -> Result<()> {
+        if !refs::contains_tag(&self.arc_path, name) {
+            return Err(ArcError::NoSuchTag(name.clone()));
+        }
+        
+        refs::delete_tag(&self.arc_path, name)?;
+        Ok(())
     }
     
     /*
      * Add/Remove tracked files
      */
-    pub fn add_file(&mut self, rel_path: &String) {
+    /// Stages a file's working-tree content, given relative to the repo
+    /// root, tracking it if it wasn't. Untracked files matched by .arcignore
+    /// are refused unless `force` is set. Returns whether the file wasn't
+    /// tracked before.
+    pub fn add_file(&mut self, rel_path: &String, force: bool) -> Result<bool> {
         let full_path = mach::join_paths(&self.root_path, rel_path);
-        assert!(mach::check_path(&full_path), "File doesn't exist!");
+        if !mach::check_path(&full_path) {
+            return Err(ArcError::FileNotFound(rel_path.clone()));
+        }
+        if !force && !self.repo.tracked_files.contains(rel_path) && ignore::load(&self.root_path)?.is_ignored(rel_path, false) {
+            return Err(ArcError::Ignored(rel_path.clone()));
+        }
+        
+        let new = !self.repo.tracked_files.contains(rel_path);
+        self.stage(rel_path)?;
+        Ok(new)
+    }
+    
+    /// Stages every file the paths name: a file, everything below a
+    /// directory ("" is the whole tree), or the files matching a glob such as
+    /// `src/**/*.rs`. Untracked ignored files below a directory or matching a
+    /// glob are skipped unless `force` is set. Returns the files whose staged
+    /// content changed, counting newly tracked ones.
+    pub fn add_paths(&mut self, rel_paths: &[String], force: bool) -> Result<Vec<String>> {
+        let ignore = ignore::load(&self.root_path)?;
+        let all_files = mach::list_files(&self.root_path)?;
         
+        let mut staged = Vec::new();
+        for p in rel_paths {
+            let matched: Vec<&String> = if ignore::is_glob(p) {
+                all_files.iter().filter(|f| ignore::glob_match(p, f)).collect()
+            } else if mach::check_dir(&mach::join_paths(&self.root_path, p)) {
+                let prefix = format!("{}/", p);
+                all_files.iter().filter(|f| p.is_empty() || f.starts_with(&prefix)).collect()
+            } else {
+                let old = self.index.get(p).cloned();
+                self.add_file(p, force)?;
+                if self.index.get(p) != old.as_ref() && !staged.contains(p) {
+                    staged.push(p.clone());
+                }
+                continue;
+            };
+            if matched.is_empty() && ignore::is_glob(p) {
+                return Err(ArcError::FileNotFound(p.clone()));
+            }
+            
+            for f in matched {
+                let tracked = self.repo.tracked_files.contains(f);
+                if (tracked || force || !ignore.is_ignored(f, false)) && self.stage(f)? && !staged.contains(f) {
+                    staged.push(f.clone());
+                }
+            }
+        }
+        Ok(staged)
+    }
+    
+    // Puts a file's working-tree content in the store and the index,
+    // returning whether its staged content changed
+    fn stage(&mut self, rel_path: &String) -> Result<bool> {
+        let old = self.index.get(rel_path).cloned();
+        self.stage_content(rel_path, &mach::read_bytes(&self.root_path, rel_path)?)?;
+        Ok(self.index.get(rel_path) != old.as_ref())
+    }
+    
+    /// Stages the given content for a file instead of its working-tree
+    /// content, tracking it if it wasn't
+    pub fn stage_content(&mut self, rel_path: &String, data: &[u8]) -> Result<()> {
+        let h = store::put(&self.arc_path, data)?;
         if !self.repo.tracked_files.contains(rel_path) {
             self.repo.tracked_files.push(rel_path.clone());
         }
+        self.index.insert(rel_path.clone(), h);
+        Ok(())
+    }
+    
+    fn untrack(&mut self, rel_path: &String) {
+        self.repo.tracked_files.retain(|f| f != rel_path);
+        self.index.remove(rel_path);
+    }
+    
+    /// Stages every tracked file's working-tree content, and stops tracking
+    /// the ones deleted from it, which are returned
+    pub fn stage_tracked(&mut self) -> Result<Vec<String>> {
+        let mut missing = Vec::new();
+        for f in self.repo.tracked_files.clone() {
+            if mach::check_path(&mach::join_paths(&self.root_path, &f)) {
+                self.stage(&f)?;
+            } else {
+                self.untrack(&f);
+                missing.push(f);
+            }
+        }
+        Ok(missing)
+    }
+    
+    /// Unstages the files the paths name (a file, or everything below a
+    /// directory; "" is the whole tree), leaving the working tree alone:
+    /// their staged content goes back to the current revision's, files the
+    /// revision doesn't have stop being tracked and removed files are
+    /// tracked again. Returns the files whose staged content changed.
+    pub fn reset(&mut self, rel_paths: &[String]) -> Result<Vec<String>> {
+        let head = self.head_rev()?;
+        let mut candidates = self.repo.tracked_files.clone();
+        for f in head.iter().flat_map(|r| r.get_files()) {
+            if !candidates.contains(&f) {
+                candidates.push(f);
+            }
+        }
+        
+        let mut files: Vec<String> = Vec::new();
+        for p in rel_paths {
+            let matched: Vec<&String> = candidates.iter().filter(|f| diff::matches_path(f, p)).collect();
+            if matched.is_empty() {
+                return Err(ArcError::NotTracked(p.clone()));
+            }
+            for f in matched {
+                if !files.contains(f) {
+                    files.push(f.clone());
+                }
+            }
+        }
         
-        println!("Added to tracked files @ {}", rel_path);
+        let mut unstaged = Vec::new();
+        for f in files {
+            let head_blob = head.as_ref().and_then(|r| r.get_blob(&f));
+            if head_blob == self.index.get(&f) {
+                continue;
+            }
+            match head_blob {
+                Some(h) => {
+                    if !self.repo.tracked_files.contains(&f) {
+                        self.repo.tracked_files.push(f.clone());
+                    }
+                    self.index.insert(f.clone(), h.clone());
+                },
+                None => self.untrack(&f),
+            }
+            unstaged.push(f);
+        }
+        unstaged.sort();
+        Ok(unstaged)
     }
     
-    pub fn remove_file(&mut self, rel_path: &String) {
-        self.repo.tracked_files.retain(|x| x != rel_path);
+    /// Stops tracking the files the paths name (a file, or everything tracked
+    /// below a directory) and deletes them from the working tree unless
+    /// `cached` is set. Files with uncommitted changes are only deleted with
+    /// `force`. Returns the files that are no longer tracked.
+    pub fn remove_paths(&mut self, rel_paths: &[String], cached: bool, force: bool) -> Result<Vec<String>> {
+        let mut files: Vec<String> = Vec::new();
+        for p in rel_paths {
+            let matched: Vec<&String> = self.repo.tracked_files.iter().filter(|f| diff::matches_path(f, p)).collect();
+            if matched.is_empty() {
+                return Err(ArcError::NotTracked(p.clone()));
+            }
+            for f in matched {
+                if !files.contains(f) {
+                    files.push(f.clone());
+                }
+            }
+        }
         
-        let full_path = mach::join_paths(&self.root_path, rel_path);
-        if mach::check_path(&full_path) {
-            mach::del_file(&self.root_path, rel_path);
+        if !cached && !force {
+            let dirty = self.find_dirty_files(&files)?;
+            if !dirty.is_empty() {
+                return Err(ArcError::DirtyFiles(dirty));
+            }
+        }
+        
+        for f in &files {
+            self.untrack(f);
+        }
+        if !cached {
+            mach::del_files(&self.root_path, &files)?;
+        }
+        files.sort();
+        Ok(files)
+    }
+    
+    /// The files whose working-tree or staged content differs from the
+    /// current revision, counting ones the revision doesn't have; files
+    /// missing from the working tree have nothing to lose and don't count
+    pub fn find_dirty_files(&self, files: &[String]) -> Result<Vec<String>> {
+        let head = self.head_rev()?;
+        let mut dirty = Vec::new();
+        for f in files {
+            if !mach::check_path(&mach::join_paths(&self.root_path, f)) {
+                continue;
+            }
+            match head.as_ref().and_then(|r| r.get_blob(f)) {
+                Some(h) if self.index.get(f) == Some(h) && h == &store::hash_file(&self.root_path, f)? => {},
+                _ => dirty.push(f.clone()),
+            }
+        }
+        Ok(dirty)
+    }
+    
+    /// Copies the files the paths name (a file, everything below a directory
+    /// or a glob) from a revision into the working tree and the index,
+    /// tracking them again if they were removed. Returns the restored files.
+    pub fn restore(&mut self, rev_id_str: &String, rel_paths: &[String]) -> Result<Vec<String>> {
+        let rev_id = self.resolve_known(rev_id_str)?;
+        let rev = rev::open(self, &rev_id)?;
+        let rev_files = rev.get_files();
+        
+        let mut files: Vec<String> = Vec::new();
+        for p in rel_paths {
+            let matched: Vec<&String> = rev_files.iter().filter(|f| {
+                if ignore::is_glob(p) { ignore::glob_match(p, f) } else { diff::matches_path(f, p) }
+            }).collect();
+            if matched.is_empty() {
+                return Err(ArcError::NotInRevision(p.clone(), rev_id));
+            }
+            for f in matched {
+                if !files.contains(f) {
+                    files.push(f.clone());
+                }
+            }
         }
         
-        println!("Removed from tracked files @ {}", rel_path);
+        for f in &files {
+            if let Some(h) = rev.get_blob(f) {
+                store::copy_out(&self.arc_path, h, &self.root_path, f)?;
+                self.index.insert(f.clone(), h.clone());
+            }
+            if !self.repo.tracked_files.contains(f) {
+                self.repo.tracked_files.push(f.clone());
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+    
+    /// The tracked files
+    pub fn get_tracked_files(&self) -> &Vec<String> {
+        &self.repo.tracked_files
+    }
+    
+    /// Staged blob of a tracked file
+    pub fn get_staged_blob(&self, f_rel_path: &String) -> Option<&String> {
+        self.index.get(f_rel_path)
+    }
+    
+    /// Every tracked file with its staged blob
+    pub fn get_index(&self) -> &BTreeMap<String, String> {
+        &self.index
     }
     
-    pub fn update_files(&mut self, files: &Vec<String>) {
+    /// Makes these the tracked files, staging what `base` has for each, or
+    /// the working-tree content of files `base` doesn't have. Files in
+    /// neither are left untracked.
+    pub fn update_files(&mut self, files: &[String], base: Option<&rev::Rev>) -> Result<()> {
         self.repo.tracked_files.clear();
+        self.index.clear();
         for f in files {
+            let h = match base.and_then(|r| r.get_blob(f)) {
+                Some(h) => h.clone(),
+                None if mach::check_path(&mach::join_paths(&self.root_path, f)) => store::put_file(&self.arc_path, &self.root_path, f)?,
+                None => continue,
+            };
             self.repo.tracked_files.push(f.to_string());
+            self.index.insert(f.to_string(), h);
         }
+        Ok(())
     }
     
     /*
      * Commit/Checkout
      */
-    pub fn commit(&mut self) -> rev::Rev {
-        let mut rev = rev::new(self, &self.repo.cur_rev, &revid::EMPTY);
+    /// Commits the staged files on top of the current revision and moves
+    /// the active branch along
+    pub fn commit(&mut self, message: &str) -> Result<rev::Rev> {
+        self.check_no_merge()?;
+        if message.trim().is_empty() {
+            return Err(ArcError::EmptyMessage);
+        }
         
-        rev.commit(&self.repo.tracked_files);
-        rev.save();
+        let old_head = self.repo.cur_rev;
+        let mut rev = rev::new(self, &old_head, &revid::EMPTY);
+        rev.set_message(message);
+        for (f, h) in &self.index {
+            rev.set_blob(f, h);
+        }
+        rev.save()?;
         
-        self.add_rev(rev.get_id());
+        self.add_rev(rev.get_id())?;
         self.set_head_rev(rev.get_id());
-        self.save();
+        self.advance_branch(&old_head)?;
+        self.save()?;
+        Ok(rev)
+    }
+    
+    /// Checking out a branch makes it the active one; checking out a plain
+    /// revision leaves no branch active
+    pub fn checkout(&mut self, rev_id_str: &String, local: LocalChanges) -> Result<rev::Rev> {
+        let rev_id = self.resolve_known(rev_id_str)?;
+        self.check_no_merge()?;
         
-        println!("Committed -> {}", rev.get_id());
-        rev
+        let rev = self.checkout_rev(&rev_id, local)?;
+        if refs::contains_branch(&self.arc_path, rev_id_str) {
+            self.repo.active_branch = Some(rev_id_str.clone());
+        } else {
+            self.repo.active_branch = None;
+        }
+        self.save()?;
+        Ok(rev)
     }
     
-    pub fn checkout(&mut self, rev_id_str: &String) -> rev::Rev {
-        let rev_id = revid::parse(&rev_id_str);
-        assert!(self.contains_rev(&rev_id), "Invalid revision!");
+    /// Moves to a revision such as a merge result, taking the active branch
+    /// along when the revision descends from the current one; otherwise no
+    /// branch is left active
+    pub fn advance(&mut self, rev_id_str: &String, local: LocalChanges) -> Result<rev::Rev> {
+        let rev_id = self.resolve_known(rev_id_str)?;
+        self.check_no_merge()?;
         
-        mach::del_files(&self.root_path, &self.repo.tracked_files);
-        let rev = rev::open(self, &rev_id);
-        rev.checkout();
+        let old_head = self.repo.cur_rev;
+        let rev = self.checkout_rev(&rev_id, local)?;
+        self.advance_branch(&old_head)?;
+        self.save()?;
+        Ok(rev)
+    }
+    
+    // Staying on the current revision leaves the working tree alone, unless
+    // the local changes are to be discarded
+    fn checkout_rev(&mut self, rev_id: &RevID, local: LocalChanges) -> Result<rev::Rev> {
+        let rev = rev::open(self, rev_id)?;
+        if *rev_id == self.repo.cur_rev && local != LocalChanges::Discard {
+            return Ok(rev);
+        }
+        
+        let carried = match local {
+            LocalChanges::Discard => Vec::new(),
+            _ => {
+                let dirty = self.find_dirty_files(&self.repo.tracked_files)?;
+                if local == LocalChanges::Refuse && !dirty.is_empty() {
+                    return Err(ArcError::DirtyFiles(dirty));
+                }
+                self.carry_changes(&dirty, &rev)?
+            }
+        };
+        
+        mach::del_files(&self.root_path, &self.repo.tracked_files)?;
+        rev.checkout()?;
+        let mut files = rev.get_files();
+        for (f, content) in &carried {
+            match content {
+                Some(data) => {
+                    mach::write_bytes(&self.root_path, f, data)?;
+                    if !files.contains(f) {
+                        files.push(f.clone());
+                    }
+                },
+                None => {
+                    mach::del_file(&self.root_path, f)?;
+                    files.retain(|x| x != f);
+                },
+            }
+        }
         
-        self.update_files(rev.get_files());
+        self.update_files(&files, Some(&rev))?;
         self.set_head_rev(rev.get_id());
-        self.save();
+        Ok(rev)
+    }
+    
+    // Merges the changes made to each dirty file since the current revision
+    // into the target revision's version of it, giving the content to write
+    // (None to delete the file) or CarryConflict if any don't merge cleanly
+    fn carry_changes(&self, dirty: &[String], target: &rev::Rev) -> Result<Vec<(String, Option<Vec<u8>>)>> {
+        let head = self.head_rev()?;
+        let mut carried = Vec::new();
+        let mut conflicts = Vec::new();
+        for f in dirty {
+            let local = mach::read_bytes(&self.root_path, f)?;
+            let base_blob = head.as_ref().and_then(|r| r.get_blob(f));
+            // The target didn't touch the file, so the local version goes over as it is
+            if base_blob == target.get_blob(f) {
+                carried.push((f.clone(), Some(local)));
+                continue;
+            }
+            
+            let base = match &head {
+                Some(r) => r.read_file(f)?,
+                None => None,
+            };
+            let local = Some(String::from_utf8_lossy(&local).to_string());
+            match merge::merge3(base, local, target.read_file(f)?) {
+                merge::Merged::Clean(m) => carried.push((f.clone(), m.map(|s| s.into_bytes()))),
+                merge::Merged::Conflict(_) => conflicts.push(f.clone()),
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(ArcError::CarryConflict(conflicts, *target.get_id()));
+        }
+        Ok(carried)
+    }
+    
+    /*
+     * Stash
+     */
+    /// Saves the changes to tracked files as a stash and resets the working
+    /// tree to the current revision. Returns the stash, or None if there
+    /// were no changes to save.
+    pub fn stash_push(&mut self, message: Option<&str>) -> Result<Option<rev::Rev>> {
+        self.check_no_merge()?;
+        let head_id = self.repo.cur_rev;
+        if head_id.is_empty() {
+            return Err(ArcError::NoRevisions);
+        }
+        if status::compute(self)?.is_clean() {
+            return Ok(None);
+        }
+        
+        let head = rev::open(self, &head_id)?;
+        let on = self.repo.active_branch.clone().unwrap_or_else(|| head_id.to_string());
+        let mut rev = rev::new(self, &head_id, &revid::EMPTY);
+        match message {
+            Some(m) => rev.set_message(m),
+            None => rev.set_message(&format!("WIP on {}: {}", on, head.get_summary())),
+        }
+        rev.commit(&self.repo.tracked_files)?;
+        rev.save()?;
+        
+        let mut stashes = stash::load(&self.arc_path)?;
+        stashes.insert(0, *rev.get_id());
+        stash::save(&self.arc_path, &stashes)?;
         
-        println!("Checked out {}", rev_id);
-        rev
+        self.checkout_rev(&head_id, LocalChanges::Discard)?;
+        self.save()?;
+        Ok(Some(rev))
+    }
+    
+    /// The stashes, most recent first
+    pub fn get_stashes(&self) -> Result<Vec<rev::Rev>> {
+        let mut stashes = Vec::new();
+        for id in stash::load(&self.arc_path)? {
+            stashes.push(rev::open(self, &id)?);
+        }
+        Ok(stashes)
+    }
+    
+    /// Applies a stash's changes onto the current revision with a three-way
+    /// merge and drops it. On conflicts the conflicted files are left in the
+    /// working tree with their markers and the stash is kept. Returns the
+    /// conflicted files.
+    pub fn stash_pop(&mut self, index: usize) -> Result<Vec<String>> {
+        self.check_no_merge()?;
+        let stash_id = *stash::load(&self.arc_path)?.get(index).ok_or(ArcError::NoSuchStash(index))?;
+        let dirty = self.find_dirty_files(&self.repo.tracked_files)?;
+        if !dirty.is_empty() {
+            return Err(ArcError::DirtyFiles(dirty));
+        }
+        
+        let stash_rev = rev::open(self, &stash_id)?;
+        let base_rev = rev::open(self, stash_rev.get_parent_trunk_id())?;
+        let head_rev = match self.head_rev()? {
+            Some(r) => r,
+            None => rev::new(self, &revid::EMPTY, &revid::EMPTY),
+        };
+        let mut applied = rev::new(self, &revid::EMPTY, &revid::EMPTY);
+        let conflicts = applied.merge(&base_rev, &head_rev, &stash_rev)?;
+        
+        mach::del_files(&self.root_path, &self.repo.tracked_files)?;
+        applied.checkout()?;
+        let mut files = applied.get_files();
+        for (f, content) in &conflicts {
+            mach::write_bytes(&self.root_path, f, content.as_bytes())?;
+            files.push(f.clone());
+        }
+        // The stash's changes come back unstaged, but for the files it added
+        self.update_files(&files, Some(&head_rev))?;
+        self.save()?;
+        
+        if conflicts.is_empty() {
+            self.stash_drop(index)?;
+        }
+        Ok(conflicts.into_iter().map(|(f, _)| f).collect())
+    }
+    
+    /// Deletes a stash, returning it
+    pub fn stash_drop(&mut self, index: usize) -> Result<rev::Rev> {
+        let mut stashes = stash::load(&self.arc_path)?;
+        if index >= stashes.len() {
+            return Err(ArcError::NoSuchStash(index));
+        }
+        let rev = rev::open(self, &stashes.remove(index))?;
+        stash::save(&self.arc_path, &stashes)?;
+        if !self.contains_rev(rev.get_id()) {
+            mach::del_dir(&rev.rev_path)?;
+        }
+        Ok(rev)
     }
     
     /*
      * Sync
      */
-    pub fn sync(&mut self, other_repo: &Repo) {
+    /// Copies over the revisions and tags this repo is missing
+    pub fn sync(&mut self, other_repo: &Repo) -> Result<SyncOutcome> {
+        let mut outcome = SyncOutcome {
+            revs: Vec::new(),
+            tags: Vec::new(),
+            skipped_tags: Vec::new(),
+            kept_tags: Vec::new(),
+        };
         for other_rev_id in &other_repo.repo.all_revs {
-            if !self.contains_rev(&other_rev_id) {
-                let other_rev = rev::open(&other_repo, &other_rev_id);
+            if !self.contains_rev(other_rev_id) {
+                let other_rev = rev::open(other_repo, other_rev_id)?;
+                if !other_rev.verify() {
+                    return Err(ArcError::Corrupt(format!("revision {} failed integrity check", other_rev_id)));
+                }
+                other_rev.copy_to(&self.arc_path)?;
                 
-                let dst_path = mach::join_paths(&self.arc_path, &other_rev_id.to_string());
-                other_rev.copy_to(&dst_path);
-                
-                self.add_rev(&other_rev_id);
+                self.add_rev(other_rev_id)?;
+                outcome.revs.push(*other_rev_id);
+            }
+        }
+        
+        // Tags never move, so one that exists on both sides is left alone
+        for (name, tag) in other_repo.get_tags()? {
+            match refs::read_tag(&self.arc_path, &name)? {
+                None => {
+                    if !refs::contains_branch(&self.arc_path, &name) {
+                        refs::write_tag(&self.arc_path, &name, &tag)?;
+                        outcome.tags.push(name);
+                    } else {
+                        outcome.skipped_tags.push(name);
+                    }
+                },
+                Some(local) if local.rev_id != tag.rev_id => {
+                    outcome.kept_tags.push((name, local.rev_id, tag.rev_id));
+                },
+                Some(_) => {},
             }
         }
-        self.save();
-        println!("Synchronized {} with {}", self.root_path, other_repo.root_path);
+        self.save()?;
+        Ok(outcome)
     }
     
     /*
      * Merge
      */
-    pub fn merge(&mut self, trunk_id_str: &String, other_id_str: &String) -> rev::Rev {
-        let trunk_id = revid::parse(&trunk_id_str);
-        let other_id = revid::parse(&other_id_str);
-        assert!(self.contains_rev(&trunk_id), "Invalid rev id for trunk parent!");
-        assert!(self.contains_rev(&other_id), "Invalid rev id for other parent!");
-        
-        if trunk_id == other_id {
-            println!("Already update to date: {}", trunk_id);
-            rev::open(self, &trunk_id)
+    /// Merges other into trunk. A clean merge is saved but not checked out
+    /// (see [`Repo::advance`]); on conflicts the working tree is left
+    /// mid-merge, to be finished with [`Repo::merge_continue`] or
+    /// [`Repo::merge_abort`].
+    ///
+    /// `local` says what happens to uncommitted changes when the merge needs
+    /// a new revision; pass the same to [`Repo::advance`]. Carrying them
+    /// into a merge that stops on conflicts isn't possible, so that fails
+    /// with [`ArcError::DirtyFiles`].
+    pub fn merge(&mut self, trunk_id_str: &String, other_id_str: &String, local: LocalChanges) -> Result<merge::MergeOutcome> {
+        let trunk_id = self.resolve_known(trunk_id_str)?;
+        let other_id = self.resolve_known(other_id_str)?;
+        self.check_no_merge()?;
+        
+        if merge::can_reach_rev(self, &trunk_id, &other_id) {
+            return Ok(merge::MergeOutcome::UpToDate(rev::open(self, &trunk_id)?));
         } else if merge::can_reach_rev(self, &other_id, &trunk_id) {
-            println!("Fast-forward -> {}", other_id);
-            rev::open(self, &other_id)
-        } else if merge::can_reach_rev(self, &trunk_id, &other_id) {
-            println!("Fast-forward -> {}", trunk_id);
-            rev::open(self, &trunk_id)
-        } else {
-            let ancestor_id = merge::find_common_ancestor(self, &trunk_id, &other_id);
-            assert!(!ancestor_id.is_empty(), "No common ancestor revision!");
-            
-            let ancestor_rev = rev::open(self, &ancestor_id);
-            let trunk_rev = rev::open(self, &trunk_id);
-            let other_rev = rev::open(self, &other_id);
-            
-            let mut rev = rev::new(self, &self.repo.cur_rev, &revid::EMPTY);
-            rev.merge(&ancestor_rev, &trunk_rev, &other_rev);
-            rev.save();
-            
-            self.add_rev(rev.get_id());
-            self.save();
+            return Ok(merge::MergeOutcome::FastForward(rev::open(self, &other_id)?));
+        }
+        
+        let dirty = match local {
+            LocalChanges::Discard => Vec::new(),
+            _ => self.find_dirty_files(&self.repo.tracked_files)?,
+        };
+        if local == LocalChanges::Refuse && !dirty.is_empty() {
+            return Err(ArcError::DirtyFiles(dirty));
+        }
+        
+        let (ancestor_rev, bases) = merge::find_merge_base(self, &trunk_id, &other_id)?.ok_or(ArcError::NoCommonAncestor(trunk_id, other_id))?;
+        let trunk_rev = rev::open(self, &trunk_id)?;
+        let other_rev = rev::open(self, &other_id)?;
+        
+        let mut rev = rev::new(self, &trunk_id, &other_id);
+        rev.set_message(&format!("Merge {} into {}", other_id, trunk_id));
+        let conflicts = rev.merge(&ancestor_rev, &trunk_rev, &other_rev)?;
+        
+        if conflicts.is_empty() {
+            // Nothing is saved unless the local changes can follow
+            self.carry_changes(&dirty, &rev)?;
+            rev.save()?;
             
-            println!("Merged {} and {} -> {}", trunk_id, other_id, rev.get_id());
-            rev
+            self.add_rev(rev.get_id())?;
+            self.save()?;
+            return Ok(merge::MergeOutcome::Merged { rev, bases });
+        }
+        
+        if !dirty.is_empty() {
+            return Err(ArcError::DirtyFiles(dirty));
+        }
+        
+        // Leave the merge result in the working tree for the user to fix
+        mach::del_files(&self.root_path, &self.repo.tracked_files)?;
+        rev.checkout()?;
+        let mut files = rev.get_files();
+        for (f, content) in &conflicts {
+            mach::write_bytes(&self.root_path, f, content.as_bytes())?;
+            if trunk_rev.get_blob(f).is_some() {
+                files.push(f.clone());
+            }
+        }
+        // Conflicted files keep trunk's version in the index (or stay out of
+        // it if trunk doesn't have them) until resolve --mark stages them
+        self.update_files(&files, Some(&rev))?;
+        for (f, _) in &conflicts {
+            if let Some(h) = trunk_rev.get_blob(f) {
+                self.index.insert(f.clone(), h.clone());
+            }
+        }
+        self.save()?;
+        
+        let state = merge::MergeState {
+            orig_rev: self.repo.cur_rev,
+            trunk: trunk_id,
+            other: other_id,
+            conflicts: conflicts.into_iter().map(|(f, _)| f).collect(),
+            resolved: Vec::new(),
+        };
+        state.save(&self.arc_path)?;
+        Ok(merge::MergeOutcome::Conflicts { files: state.conflicts, bases })
+    }
+    
+    /// Marks a conflicted file of the merge in progress as resolved and
+    /// stages it
+    pub fn resolve_mark(&mut self, rel_path: &String) -> Result<()> {
+        let mut state = merge::load_state(&self.arc_path)?.ok_or(ArcError::NoMergeInProgress)?;
+        if !state.conflicts.contains(rel_path) {
+            return Err(ArcError::NotInConflict(rel_path.clone()));
+        }
+        
+        if !state.resolved.contains(rel_path) {
+            state.resolved.push(rel_path.clone());
+        }
+        if mach::check_path(&mach::join_paths(&self.root_path, rel_path)) {
+            self.stage(rel_path)?;
+        } else {
+            self.untrack(rel_path);
+        }
+        self.save()?;
+        state.save(&self.arc_path)?;
+        Ok(())
+    }
+    
+    /// Commits the merge in progress once all its conflicts are resolved
+    pub fn merge_continue(&mut self) -> Result<rev::Rev> {
+        let state = merge::load_state(&self.arc_path)?.ok_or(ArcError::NoMergeInProgress)?;
+        let unresolved = state.unresolved();
+        if !unresolved.is_empty() {
+            return Err(ArcError::UnresolvedConflicts(unresolved));
+        }
+        
+        let mut rev = rev::new(self, &state.trunk, &state.other);
+        rev.set_message(&format!("Merge {} into {}", state.other, state.trunk));
+        for (f, h) in &self.index {
+            rev.set_blob(f, h);
         }
+        rev.save()?;
+        
+        self.add_rev(rev.get_id())?;
+        self.set_head_rev(rev.get_id());
+        self.advance_branch(&state.orig_rev)?;
+        self.save()?;
+        merge::clear_state(&self.arc_path)?;
+        Ok(rev)
+    }
+    
+    /// Gives up on the merge in progress, returning the revision it went back to
+    pub fn merge_abort(&mut self) -> Result<RevID> {
+        let state = merge::load_state(&self.arc_path)?.ok_or(ArcError::NoMergeInProgress)?;
+        
+        // Conflicted files trunk doesn't have aren't tracked, but go too
+        mach::del_files(&self.root_path, &self.repo.tracked_files)?;
+        mach::del_files(&self.root_path, &state.conflicts)?;
+        if state.orig_rev.is_empty() {
+            self.update_files(&[], None)?;
+        } else {
+            let rev = rev::open(self, &state.orig_rev)?;
+            rev.checkout()?;
+            self.update_files(&rev.get_files(), Some(&rev))?;
+        }
+        self.set_head_rev(&state.orig_rev);
+        self.save()?;
+        merge::clear_state(&self.arc_path)?;
+        Ok(state.orig_rev)
     }
 }
 
-pub fn init(root_path: &String) -> Repo {
+/// Creates a repo, and its directory if needed, with `main` as the active branch
+pub fn init(root_path: &String) -> Result<Repo> {
     if !mach::check_path(root_path) {
-        mach::create_dir_all(root_path);
+        mach::create_dir_all(root_path)?;
     }
     
     let arc_path = mach::join_paths(root_path, &".arc".to_string());
-    assert!(!mach::check_path(&arc_path), "Repo already initialized!");
-    mach::create_dir_all(&arc_path);
+    if mach::check_path(&arc_path) {
+        return Err(ArcError::RepoExists(root_path.clone()));
+    }
+    mach::create_dir_all(&arc_path)?;
+    mach::create_dir_all(&store::objects_path(&arc_path))?;
     
     let repo = RepoInfo {
         upstream: "None".to_string(),
         root_path: root_path.clone(),
         tracked_files: Vec::new(),
         all_revs: Vec::new(),
-        cur_rev: revid::EMPTY
+        cur_rev: revid::EMPTY,
+        active_branch: Some("main".to_string()),
     };
     
     let r = Repo {
         root_path: root_path.clone(),
-        arc_path: arc_path.clone(),
-        repo: repo
+        arc_path,
+        repo,
+        graph: graph::new(),
+        index: BTreeMap::new(),
     };
     
-    r.save();
-    println!("Initialized repo @ {}", root_path);
-    r
+    r.save()?;
+    Ok(r)
 }
 
-pub fn open(root_path: &String) -> Repo {
+/// Opens the repo rooted at the given path
+pub fn open(root_path: &String) -> Result<Repo> {
     let arc_path = mach::join_paths(root_path, &".arc".to_string());
-    assert!(mach::check_path(&arc_path), "Repo doesn't exist!");
+    if !mach::check_path(&arc_path) {
+        return Err(ArcError::NotARepo(root_path.clone()));
+    }
     
-    let json = mach::read_line(&arc_path, &String::from("repo.json"));
-    let repo: RepoInfo = serde_json::from_str(&json).expect("Unable to open repository, unexpected repo config file!");
+    let json = mach::read_line(&arc_path, &String::from("repo.json"))?;
+    let repo: RepoInfo = serde_json::from_str(&json).map_err(ArcError::corrupt("repo.json"))?;
     
-    Repo {
+    let mut r = Repo {
         root_path: root_path.clone(),
         arc_path: arc_path.clone(),
-        repo: repo
+        repo,
+        graph: graph::new(),
+        index: BTreeMap::new(),
+    };
+    let old_revs = r.upgrade_revs()?;
+    
+    // Repos from before the staging area start with the current revision's
+    // content staged
+    match index::load(&arc_path)? {
+        Some(i) => r.index = i,
+        None => {
+            let head = r.head_rev()?;
+            let files = r.repo.tracked_files.clone();
+            r.update_files(&files, head.as_ref())?;
+        },
+    }
+    
+    // Repos from before the index existed, or whose index fell behind, get it rebuilt
+    match graph::open(&arc_path)? {
+        Some(g) if r.repo.all_revs.iter().all(|id| g.contains(id)) => r.graph = g,
+        _ => r.rebuild_graph()?,
     }
+    
+    if !old_revs.is_empty() {
+        r.save()?;
+        for id in &old_revs {
+            mach::del_dir(&mach::join_paths(&arc_path, &id.to_string()))?;
+        }
+    }
+    Ok(r)
 }
-
@@ -1,34 +1,74 @@
 use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
 use std::fmt;
 use crate::mach;
+use crate::store;
 use crate::revid;
 use crate::revid::RevID;
 use crate::repo;
 use crate::merge;
+use crate::error::{ArcError, Result};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct RevInfo {
     rev_id: RevID,
+    parent_trunk: RevID,
+    parent_other: RevID,
+    files: BTreeMap<String, String>,
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    author: String,
+    #[serde(default)]
+    timestamp: u64,
+}
+
+// rev.json as arc wrote it before the blob store, when each revision dir held
+// a copy of its files and IDs were random
+#[derive(Deserialize)]
+struct OldRevInfo {
     parent_trunk: RevID,
     parent_other: RevID,
     files: Vec<String>,
 }
 
+/// A revision: a snapshot of the tracked files plus its parents and message.
+/// A new revision gets its ID when it is saved.
 pub struct Rev {
+    /// Root of the working tree
     pub root_path: String,
+    /// The repo's .arc dir
     pub arc_path: String,
+    /// Dir holding rev.json, empty until the revision is saved
     pub rev_path: String,
     rev: RevInfo,
 }
 
+impl RevInfo {
+    // The ID is derived from everything else in the revision, so it is
+    // hashed with the ID field itself blanked out
+    fn content_id(&self) -> RevID {
+        let mut content = self.clone();
+        content.rev_id = revid::EMPTY;
+        let serialized = serde_json::to_vec(&content).unwrap();
+        revid::from_content(&serialized)
+    }
+}
+
 impl fmt::Display for RevInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Revision ID: {}\n", self.rev_id)?;
-        write!(f, "Parent ID (trunk): {}\n", self.parent_trunk)?;
-        write!(f, "Parent ID (other): {}\n", self.parent_other)?;
-        write!(f, "All Files:\n")?;
-        for l in &self.files {
-            write!(f, "  {}\n", l.to_string())?;
+        writeln!(f, "Revision ID: {}", self.rev_id)?;
+        writeln!(f, "Parent ID (trunk): {}", self.parent_trunk)?;
+        writeln!(f, "Parent ID (other): {}", self.parent_other)?;
+        writeln!(f, "Author: {}", self.author)?;
+        writeln!(f, "Date: {}", mach::format_timestamp(self.timestamp))?;
+        writeln!(f, "Message:")?;
+        for l in self.message.lines() {
+            writeln!(f, "    {}", l)?;
+        }
+        writeln!(f, "All Files:")?;
+        for (l, h) in &self.files {
+            writeln!(f, "  {} ({})", l, h)?;
         }
         Ok(())
     }
@@ -36,121 +76,229 @@ impl fmt::Display for RevInfo {
 
 impl fmt::Display for Rev {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Root Path @ {}\n", self.root_path)?;
-        write!(f, ".arc Path @ {}\n", self.arc_path)?;
-        write!(f, "Revision Path @ {}\n", self.rev_path)?;
-        write!(f, "{}\n", self.rev)?;
+        writeln!(f, "Root Path @ {}", self.root_path)?;
+        writeln!(f, ".arc Path @ {}", self.arc_path)?;
+        writeln!(f, "Revision Path @ {}", self.rev_path)?;
+        writeln!(f, "{}", self.rev)?;
         Ok(())
     }
 }
 
 impl Rev {
-    pub fn save(&self) {
+    /// Derives the ID and writes rev.json into the revision dir
+    pub fn save(&mut self) -> Result<()> {
+        self.rev.rev_id = self.rev.content_id();
+        self.rev_path = mach::join_paths(&self.arc_path, &self.rev.rev_id.to_string());
+        mach::create_dir_all(&self.rev_path)?;
+        
         let serialized = serde_json::to_string(&self.rev).unwrap();
-        mach::write_string(&self.rev_path, &String::from("rev.json"), &serialized);
+        mach::write_string(&self.rev_path, &String::from("rev.json"), &serialized)
     }
     
+    /// The ID, EMPTY until the revision is saved
     pub fn get_id(&self) -> &RevID {
         &self.rev.rev_id
     }
     
+    /// The ID as 32 hex digits
     pub fn get_id_str(&self) -> String {
         self.rev.rev_id.to_string()
     }
     
-    pub fn get_files(&self) -> &Vec<String> {
-        &self.rev.files
+    /// Whether the ID still matches the content
+    pub fn verify(&self) -> bool {
+        self.rev.rev_id == self.rev.content_id()
+    }
+    
+    /// Paths of the files in this revision, sorted
+    pub fn get_files(&self) -> Vec<String> {
+        self.rev.files.keys().cloned().collect()
     }
     
+    /// Store hash of a file in this revision
+    pub fn get_blob(&self, f_rel_path: &String) -> Option<&String> {
+        self.rev.files.get(f_rel_path)
+    }
+    
+    /// Content of a file in this revision, None if it isn't in it
+    pub fn read_file(&self, f_rel_path: &String) -> Result<Option<String>> {
+        self.get_blob(f_rel_path).map(|h| store::get_string(&self.arc_path, h)).transpose()
+    }
+    
+    /// The full commit message
+    pub fn get_message(&self) -> &String {
+        &self.rev.message
+    }
+    
+    /// First line of the message
+    pub fn get_summary(&self) -> String {
+        self.rev.message.lines().next().unwrap_or("").to_string()
+    }
+    
+    /// Who committed the revision
+    pub fn get_author(&self) -> &String {
+        &self.rev.author
+    }
+    
+    /// When the revision was made, in seconds since the epoch
+    pub fn get_timestamp(&self) -> u64 {
+        self.rev.timestamp
+    }
+    
+    /// Stores the content and puts it into this revision
+    pub fn set_file(&mut self, f_rel_path: &str, data: &[u8]) -> Result<()> {
+        let h = store::put(&self.arc_path, data)?;
+        self.rev.files.insert(f_rel_path.to_string(), h);
+        Ok(())
+    }
+    
+    /// Puts a blob that is already in the store into this revision
+    pub fn set_blob(&mut self, f_rel_path: &str, h: &str) {
+        self.rev.files.insert(f_rel_path.to_string(), h.to_string());
+    }
+    
+    /// Sets the commit message; call before saving
+    pub fn set_message(&mut self, message: &str) {
+        self.rev.message = message.to_string();
+    }
+    
+    /// The revision this one was committed on top of (EMPTY for a root)
     pub fn get_parent_trunk_id(&self) -> &RevID {
         &self.rev.parent_trunk
     }
     
+    /// The revision merged in (EMPTY unless this is a merge)
     pub fn get_parent_other_id(&self) -> &RevID {
         &self.rev.parent_other
     }
     
-    pub fn commit(&mut self, tracked_files: &Vec<String>) -> Vec<String> {
+    /// Snapshots the tracked files from the working tree, returning the ones
+    /// that no longer exist
+    pub fn commit(&mut self, tracked_files: &[String]) -> Result<Vec<String>> {
         let mut missing_files = Vec::new();
         for f_rel_path in tracked_files {
-            if mach::check_path(&mach::join_paths(&self.root_path, &f_rel_path)) {
-                mach::copy_file(&self.rev_path, &self.root_path, f_rel_path);
-                self.rev.files.push(f_rel_path.clone());
+            if mach::check_path(&mach::join_paths(&self.root_path, f_rel_path)) {
+                let h = store::put_file(&self.arc_path, &self.root_path, f_rel_path)?;
+                self.rev.files.insert(f_rel_path.clone(), h);
             } else {
                 missing_files.push(f_rel_path.clone());
             }
         }
-        missing_files
+        Ok(missing_files)
     }
     
-    pub fn checkout(&self) {
-        for f_rel_path in &self.rev.files {
-            assert!(mach::check_path(&mach::join_paths(&self.rev_path, &f_rel_path)), "File missing in a revision!");
-            mach::copy_file(&self.root_path, &self.rev_path, f_rel_path);
+    /// Writes this revision's files into the working tree
+    pub fn checkout(&self) -> Result<()> {
+        for (f_rel_path, h) in &self.rev.files {
+            store::copy_out(&self.arc_path, h, &self.root_path, f_rel_path)?;
         }
+        Ok(())
     }
     
-    pub fn copy_to(&self, dst_path: &String) {
-        mach::create_dir_all(dst_path);
+    /// Copies the revision and its blobs into another repo's .arc dir
+    pub fn copy_to(&self, dst_arc_path: &String) -> Result<()> {
+        let dst_path = mach::join_paths(dst_arc_path, &self.rev.rev_id.to_string());
+        mach::create_dir_all(&dst_path)?;
         
-        for f_rel_path in &self.rev.files {
-            assert!(mach::check_path(&mach::join_paths(&self.root_path, &f_rel_path)), "File missing in a revision!");
-            mach::copy_file(&dst_path, &self.rev_path, f_rel_path);
+        for h in self.rev.files.values() {
+            store::copy_between(dst_arc_path, &self.arc_path, h)?;
         }
-        mach::copy_file(&dst_path, &self.rev_path, &"rev.json".to_string());
+        mach::copy_file(&dst_path, &self.rev_path, &"rev.json".to_string())
     }
     
-    pub fn merge(&mut self, ancestor_rev: &Rev, trunk_rev: &Rev, other_rev: &Rev) {
+    /// Cleanly merged files go into this revision; conflicted ones are
+    /// returned with their conflict markers instead
+    pub fn merge(&mut self, ancestor_rev: &Rev, trunk_rev: &Rev, other_rev: &Rev) -> Result<Vec<(String, String)>> {
+        let mut conflicts = Vec::new();
         let ancestor_files = ancestor_rev.get_files();
         let trunk_files = trunk_rev.get_files();
         let other_files = other_rev.get_files();
         
-        let files = merge::find_all_files(ancestor_files, trunk_files, other_files);
+        let files = merge::find_all_files(&ancestor_files, &trunk_files, &other_files);
         for f in &files {
-            let ancestor_content = if ancestor_files.contains(&f) { Some(mach::read_line(&ancestor_rev.rev_path, &f)) } else { None };
-            let trunk_content = if trunk_files.contains(&f) { Some(mach::read_line(&trunk_rev.rev_path, &f)) } else { None };
-            let other_content = if other_files.contains(&f) { Some(mach::read_line(&other_rev.rev_path, &f)) } else { None };
+            let ancestor_content = ancestor_rev.read_file(f)?;
+            let trunk_content = trunk_rev.read_file(f)?;
+            let other_content = other_rev.read_file(f)?;
             
-            let m = merge::merge3(ancestor_content, trunk_content, other_content);
-            if m.is_some() {
-                mach::write_string(&self.rev_path, &f, &m.unwrap());
-                self.rev.files.push(f.clone());
+            match merge::merge3(ancestor_content, trunk_content, other_content) {
+                merge::Merged::Clean(Some(m)) => self.set_file(f, m.as_bytes())?,
+                merge::Merged::Clean(None) => {},
+                merge::Merged::Conflict(m) => conflicts.push((f.clone(), m)),
             }
         }
+        Ok(conflicts)
     }
 }
 
+/// An unsaved revision with the given parents and no files
 pub fn new(repo: &repo::Repo, trunk_id: &RevID, other_id: &RevID) -> Rev {
     let rev = RevInfo {
-        rev_id: revid::new(),
-        parent_trunk: trunk_id.clone(),
-        parent_other: other_id.clone(),
-        files: Vec::new(),
+        rev_id: revid::EMPTY,
+        parent_trunk: *trunk_id,
+        parent_other: *other_id,
+        files: BTreeMap::new(),
+        message: String::new(),
+        author: mach::get_author(),
+        timestamp: mach::get_timestamp(),
     };
     
-    let rev_path = mach::join_paths(&repo.arc_path, &rev.rev_id.to_string());
-    mach::create_dir_all(&rev_path);
-    
+    // The revision dir is only known once save() derives the ID
     Rev {
         root_path: repo.root_path.clone(),
         arc_path: repo.arc_path.clone(),
-        rev_path: rev_path.clone(),
-        rev: rev
+        rev_path: String::new(),
+        rev,
     }
 }
 
-pub fn open(repo: &repo::Repo, rev_id: &RevID) -> Rev {
+/// Loads a revision of the repo
+pub fn open(repo: &repo::Repo, rev_id: &RevID) -> Result<Rev> {
     let rev_path = mach::join_paths(&repo.arc_path, &rev_id.to_string());
-    assert!(mach::check_path(&rev_path), "Revision dir doesn't exist!");
+    if !mach::check_path(&rev_path) {
+        return Err(ArcError::UnknownRevision(rev_id.to_string()));
+    }
     
-    let json = mach::read_line(&rev_path, &String::from("rev.json"));
-    let r: RevInfo = serde_json::from_str(&json).expect("Unable to open revision, bad rev file!");
+    let json = mach::read_line(&rev_path, &String::from("rev.json"))?;
+    let r: RevInfo = serde_json::from_str(&json).map_err(ArcError::corrupt("rev.json"))?;
     
-    Rev {
+    Ok(Rev {
         root_path: repo.root_path.clone(),
         arc_path: repo.arc_path.clone(),
-        rev_path: rev_path.clone(),
+        rev_path,
         rev: r
+    })
+}
+
+
+fn open_old(repo: &repo::Repo, rev_id: &RevID) -> Result<Option<OldRevInfo>> {
+    let rev_path = mach::join_paths(&repo.arc_path, &rev_id.to_string());
+    if !mach::check_path(&rev_path) {
+        return Err(ArcError::UnknownRevision(rev_id.to_string()));
     }
+    let json = mach::read_line(&rev_path, &String::from("rev.json"))?;
+    Ok(serde_json::from_str(&json).ok())
 }
 
+/// Parents of a revision from before the blob store, None if the revision
+/// is a current one
+pub fn old_parents(repo: &repo::Repo, rev_id: &RevID) -> Result<Option<(RevID, RevID)>> {
+    Ok(open_old(repo, rev_id)?.map(|r| (r.parent_trunk, r.parent_other)))
+}
+
+/// Moves a revision from before the blob store into it and saves it under
+/// an ID derived from its content, on top of the given (already upgraded)
+/// parents. The old revision dir is left for the caller to delete.
+pub fn upgrade(repo: &repo::Repo, rev_id: &RevID, trunk_id: &RevID, other_id: &RevID) -> Result<Rev> {
+    let old = open_old(repo, rev_id)?.ok_or_else(|| ArcError::Corrupt(format!("revision {} isn't from before the blob store", rev_id)))?;
+    let old_path = mach::join_paths(&repo.arc_path, &rev_id.to_string());
+    
+    // Old revisions had no author or date, so none are made up for them
+    let mut rev = new(repo, trunk_id, other_id);
+    rev.rev.author = String::new();
+    rev.rev.timestamp = 0;
+    for f in &old.files {
+        rev.set_file(f, &mach::read_bytes(&old_path, f)?)?;
+    }
+    rev.save()?;
+    Ok(rev)
+}
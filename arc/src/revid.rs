@@ -1,15 +1,25 @@
 use serde::{Serialize, Deserialize};
 use std::fmt;
 use uuid::Uuid;
+use crate::error::{ArcError, Result};
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+/// Identifies a revision. IDs are derived from the revision's content, so
+/// the same revision has the same ID in every repo.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub struct RevID {
     #[serde(rename="UUID")]
     value: uuid::Uuid,
 }
 
+/// Stands for "no revision", e.g. the parent of a root revision
 pub const EMPTY: RevID = RevID { value: Uuid::nil() };
 
+// Namespace for the name-based (v5) UUIDs that revision IDs are derived from
+const NAMESPACE: Uuid = Uuid::from_bytes([
+    0x6a, 0x3c, 0x1e, 0x52, 0x9b, 0x4d, 0x5f, 0x0e,
+    0x8c, 0x21, 0x7d, 0x4a, 0xe3, 0x90, 0x15, 0xb6,
+]);
+
 impl RevID {
     pub fn is_empty(&self) -> bool {
         self.value.is_nil()
@@ -18,20 +28,23 @@ impl RevID {
 
 impl fmt::Display for RevID {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.value.to_simple_ref().to_string())
+        write!(f, "{}", self.value.to_simple_ref())
     }
 }
 
-pub fn new() -> RevID {
+/// The ID of a revision with the given serialized content
+pub fn from_content(content: &[u8]) -> RevID {
     RevID {
-        value: Uuid::new_v4()
+        value: Uuid::new_v5(&NAMESPACE, content)
     }
 }
 
-pub fn parse(s: &String) -> RevID {
-    let id = Uuid::parse_str(s).expect("Bad revision ID format!");
-    RevID {
-        value: id
+/// Parses a full ID; see [`revspec`](crate::revspec) for the other ways to
+/// name a revision.
+pub fn parse(s: &str) -> Result<RevID> {
+    match Uuid::parse_str(s) {
+        Ok(id) => Ok(RevID { value: id }),
+        Err(_) => Err(ArcError::UnknownRevision(s.to_string())),
     }
 }
 
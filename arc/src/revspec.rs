@@ -0,0 +1,94 @@
+use crate::refs;
+use crate::repo;
+use crate::revid;
+use crate::revid::RevID;
+use crate::error::{ArcError, Result};
+
+/*
+ * Revision expressions
+ *
+ * A base naming a revision, followed by any number of steps back through
+ * history:
+ *
+ *   HEAD             the current revision
+ *   <branch>/<tag>   the revision a branch or tag points at
+ *   <id>             a full revision ID, or a unique prefix of one
+ *   <rev>^ / <rev>^N the trunk parent, or the Nth parent (^2 is the other parent)
+ *   <rev>~N          N steps back along the trunk parents
+ */
+
+// Prefixes shorter than this are too likely to be a typo
+const MIN_PREFIX_LEN: usize = 4;
+
+pub fn resolve(repo: &repo::Repo, spec: &String) -> Result<RevID> {
+    let split = spec.find(['^', '~']).unwrap_or(spec.len());
+    let mut rev_id = resolve_base(repo, &spec[..split].to_string())?;
+    
+    let steps: Vec<char> = spec[split..].chars().collect();
+    let mut i = 0;
+    while i < steps.len() {
+        let op = steps[i];
+        i += 1;
+        let start = i;
+        while i < steps.len() && steps[i].is_ascii_digit() {
+            i += 1;
+        }
+        let n = if start == i {
+            1
+        } else {
+            steps[start..i].iter().collect::<String>().parse::<usize>().map_err(|_| ArcError::BadRevision(spec.clone()))?
+        };
+        
+        match op {
+            '^' => rev_id = nth_parent(repo, &rev_id, n, spec)?,
+            '~' => {
+                for _ in 0..n {
+                    rev_id = nth_parent(repo, &rev_id, 1, spec)?;
+                }
+            },
+            _ => return Err(ArcError::BadRevision(spec.clone())),
+        }
+    }
+    Ok(rev_id)
+}
+
+fn resolve_base(repo: &repo::Repo, base: &String) -> Result<RevID> {
+    if base == "HEAD" {
+        if repo.get_head_rev().is_empty() {
+            return Err(ArcError::NoRevisions);
+        }
+        return Ok(repo.get_head_rev());
+    }
+    if let Some(id) = refs::read_branch(&repo.arc_path, base)? {
+        return Ok(id);
+    }
+    if let Some(tag) = refs::read_tag(&repo.arc_path, base)? {
+        return Ok(tag.rev_id);
+    }
+    if let Ok(id) = revid::parse(base) {
+        return Ok(id);
+    }
+    
+    let prefix = base.to_lowercase();
+    if prefix.len() >= MIN_PREFIX_LEN && prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        let matches: Vec<RevID> = repo.get_all_revs().iter().filter(|id| id.to_string().starts_with(&prefix)).cloned().collect();
+        match matches.len() {
+            0 => {},
+            1 => return Ok(matches[0]),
+            _ => return Err(ArcError::AmbiguousRevision(base.clone(), matches)),
+        }
+    }
+    Err(ArcError::UnknownRevision(base.clone()))
+}
+
+// ^0 is the revision itself, ^1 its trunk parent and ^2 its other parent
+fn nth_parent(repo: &repo::Repo, rev_id: &RevID, n: usize, spec: &String) -> Result<RevID> {
+    if n == 0 {
+        return Ok(*rev_id);
+    }
+    let parents = repo.get_graph().get_parents(rev_id);
+    if n > parents.len() {
+        return Err(ArcError::BadRevision(format!("{} has no parent number {} (in {})", rev_id, n, spec)));
+    }
+    Ok(parents[n - 1])
+}
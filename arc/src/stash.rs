@@ -0,0 +1,31 @@
+use crate::mach;
+use crate::revid::RevID;
+use crate::error::{ArcError, Result};
+
+/*
+ * Stashed changes, listed in .arc/stash
+ *
+ * A stash is a revision saved like any other but never added to the repo's
+ * history, so it stays out of all_revs, heads and sync. Its trunk parent is
+ * the revision the changes were made on top of. The list is kept most
+ * recent first, so stash 0 is the last one pushed.
+ */
+
+pub fn load(arc_path: &String) -> Result<Vec<RevID>> {
+    if !mach::check_path(&mach::join_paths(arc_path, &String::from("stash"))) {
+        return Ok(Vec::new());
+    }
+    let json = mach::read_line(arc_path, &String::from("stash"))?;
+    serde_json::from_str(&json).map_err(ArcError::corrupt("stash"))
+}
+
+pub fn save(arc_path: &String, stashes: &[RevID]) -> Result<()> {
+    let serialized = serde_json::to_string(stashes).unwrap();
+    mach::write_string(arc_path, &String::from("stash"), &serialized)
+}
+
+// Accepts "N" or "stash@{N}"
+pub fn parse_index(s: &str) -> Result<usize> {
+    let n = s.strip_prefix("stash@{").and_then(|r| r.strip_suffix('}')).unwrap_or(s);
+    n.parse::<usize>().map_err(|_| ArcError::BadArgument(format!("bad stash: {}", s)))
+}
@@ -0,0 +1,122 @@
+use std::fmt;
+use crate::mach;
+use crate::repo;
+use crate::store;
+use crate::ignore;
+use crate::error::Result;
+
+pub struct Status {
+    // Staged: the index against the current revision
+    pub modified: Vec<String>,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    // Not staged: the working tree against the index
+    pub edited: Vec<String>,
+    pub deleted: Vec<String>,
+    pub untracked: Vec<String>,
+}
+
+impl Status {
+    pub fn is_clean(&self) -> bool {
+        !self.has_staged() && self.edited.is_empty() && self.deleted.is_empty()
+    }
+    
+    pub fn has_staged(&self) -> bool {
+        !self.modified.is_empty() || !self.added.is_empty() || !self.removed.is_empty()
+    }
+    
+    // One "<staged><unstaged> <path>" line per file, for scripts: the first
+    // code is M, A or R (or a space), the second M or D (or a space), and
+    // untracked files are "??"
+    pub fn porcelain(&self) -> String {
+        let mut files: Vec<&String> = self.modified.iter().chain(&self.added).chain(&self.removed).chain(&self.edited).chain(&self.deleted).collect();
+        files.sort();
+        files.dedup();
+        
+        let mut lines = Vec::new();
+        for f in files {
+            let staged = if self.modified.contains(f) { 'M' } else if self.added.contains(f) { 'A' } else if self.removed.contains(f) { 'R' } else { ' ' };
+            let unstaged = if self.edited.contains(f) { 'M' } else if self.deleted.contains(f) { 'D' } else { ' ' };
+            lines.push(format!("{}{} {}\n", staged, unstaged, f));
+        }
+        for f in &self.untracked {
+            lines.push(format!("?? {}\n", f));
+        }
+        lines.concat()
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_clean() {
+            writeln!(f, "Nothing to commit, working tree clean")?;
+        }
+        if self.has_staged() {
+            writeln!(f, "Changes to be committed:")?;
+            for (label, files) in &[("modified:", &self.modified), ("added:", &self.added), ("removed:", &self.removed)] {
+                for l in files.iter() {
+                    writeln!(f, "  {:10} {}", label, l)?;
+                }
+            }
+        }
+        if !self.edited.is_empty() || !self.deleted.is_empty() {
+            writeln!(f, "Changes not staged for commit:")?;
+            for (label, files) in &[("modified:", &self.edited), ("deleted:", &self.deleted)] {
+                for l in files.iter() {
+                    writeln!(f, "  {:10} {}", label, l)?;
+                }
+            }
+        }
+        if !self.untracked.is_empty() {
+            writeln!(f, "Untracked files:")?;
+            for l in &self.untracked {
+                writeln!(f, "  {}", l)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn compute(repo: &repo::Repo) -> Result<Status> {
+    let mut status = Status {
+        modified: Vec::new(),
+        added: Vec::new(),
+        removed: Vec::new(),
+        edited: Vec::new(),
+        deleted: Vec::new(),
+        untracked: Vec::new(),
+    };
+    
+    let head_rev = repo.head_rev()?;
+    let tracked_files = repo.get_tracked_files();
+    
+    for (f, staged) in repo.get_index() {
+        match head_rev.as_ref().and_then(|r| r.get_blob(f)) {
+            None => status.added.push(f.clone()),
+            Some(h) if h != staged => status.modified.push(f.clone()),
+            Some(_) => {},
+        }
+        if !mach::check_path(&mach::join_paths(&repo.root_path, f)) {
+            status.deleted.push(f.clone());
+        } else if staged != &store::hash_file(&repo.root_path, f)? {
+            status.edited.push(f.clone());
+        }
+    }
+    
+    if let Some(r) = &head_rev {
+        for f in r.get_files() {
+            if repo.get_staged_blob(&f).is_none() {
+                status.removed.push(f);
+            }
+        }
+    }
+    
+    let ignore = ignore::load(&repo.root_path)?;
+    for f in mach::list_files(&repo.root_path)? {
+        if !tracked_files.contains(&f) && !ignore.is_ignored(&f, false) {
+            status.untracked.push(f);
+        }
+    }
+    
+    Ok(status)
+}
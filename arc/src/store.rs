@@ -0,0 +1,75 @@
+use sha1::Sha1;
+use crate::mach;
+use crate::error::{ArcError, Result};
+
+/*
+ * Content-addressed blob store under .arc/objects
+ *
+ * Every blob is named by the SHA-1 of its bytes, so a file that doesn't
+ * change between revisions is only ever stored once.
+ */
+
+/// The objects dir of the given .arc dir
+pub fn objects_path(arc_path: &String) -> String {
+    mach::join_paths(arc_path, &"objects".to_string())
+}
+
+/// The name a blob with these bytes is stored under
+pub fn hash(data: &[u8]) -> String {
+    Sha1::from(data).digest().to_string()
+}
+
+/// The name a file's content would be stored under
+pub fn hash_file(base_path: &String, f: &String) -> Result<String> {
+    Ok(hash(&mach::read_bytes(base_path, f)?))
+}
+
+/// Whether the store has the blob
+pub fn contains(arc_path: &String, hash: &String) -> bool {
+    mach::check_path(&mach::join_paths(&objects_path(arc_path), hash))
+}
+
+/// Stores the bytes unless they already are, returning their hash
+pub fn put(arc_path: &String, data: &[u8]) -> Result<String> {
+    let h = hash(data);
+    if !contains(arc_path, &h) {
+        mach::write_bytes(&objects_path(arc_path), &h, data)?;
+    }
+    Ok(h)
+}
+
+/// Stores a file's content, returning its hash
+pub fn put_file(arc_path: &String, base_path: &String, f: &String) -> Result<String> {
+    put(arc_path, &mach::read_bytes(base_path, f)?)
+}
+
+/// Reads a blob, failing with [`ArcError::Corrupt`] if the store doesn't have it
+pub fn get(arc_path: &String, hash: &String) -> Result<Vec<u8>> {
+    if !contains(arc_path, hash) {
+        return Err(ArcError::Corrupt(format!("blob {} missing from object store", hash)));
+    }
+    mach::read_bytes(&objects_path(arc_path), hash)
+}
+
+/// Reads a blob as text, replacing invalid UTF-8
+pub fn get_string(arc_path: &String, hash: &String) -> Result<String> {
+    Ok(String::from_utf8_lossy(&get(arc_path, hash)?).to_string())
+}
+
+/// Writes a blob to a file below `dst_path`, creating its dirs
+pub fn copy_out(arc_path: &String, hash: &String, dst_path: &String, f: &String) -> Result<()> {
+    mach::write_bytes(dst_path, f, &get(arc_path, hash)?)
+}
+
+/// Copies a blob into another repo's store, checking its content against its
+/// hash
+pub fn copy_between(dst_arc_path: &String, src_arc_path: &String, hash: &String) -> Result<()> {
+    if !contains(dst_arc_path, hash) {
+        let data = get(src_arc_path, hash)?;
+        if &self::hash(&data) != hash {
+            return Err(ArcError::Corrupt(format!("blob {} failed integrity check", hash)));
+        }
+        mach::write_bytes(&objects_path(dst_arc_path), hash, &data)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,23 @@
+# Helpers shared by the test scripts, which source it from the test dir
+
+ARC=$(pwd)/../target/debug/arc
+FIXTURES=$(pwd)
+
+# Commits every tracked file, printing the new revision
+commit() {
+    $ARC commit -a -m "$1" | sed -n 's/^Committed -> //p'
+}
+
+# Fails unless a file matches a fixture
+expect() {
+    if ! diff -q "$1" "$FIXTURES/$2" > /dev/null; then
+        echo "FAILED: $1 doesn't match $2"
+        diff "$1" "$FIXTURES/$2" || true
+        exit 1
+    fi
+}
+
+# The revision a revision expression names
+rev_of() {
+    $ARC log --oneline -n 1 "$1" | awk '{print $2}'
+}